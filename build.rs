@@ -7,12 +7,23 @@
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
+//!
+//! It also compiles `keymap.txt` into the key code tables used by `src/layout.rs`,
+//! and renders the same tables for `README.md` (a test checks that the README is up to date).
 
 use std::env;
-use std::fs::File;
+use std::fmt::Write as _;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
 
+const KEYMAP: &str = "keymap.txt";
+const ROWS: usize = 4;
+const COLS: usize = 12;
+// READMEのこの2行の間に表を載せる
+const README_BEGIN: &str = "<!-- keymap begin (generated from keymap.txt) -->";
+const README_END: &str = "<!-- keymap end -->";
+
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
@@ -28,4 +39,273 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+
+    println!("cargo:rerun-if-changed={KEYMAP}");
+    let source = fs::read_to_string(KEYMAP).unwrap();
+    let layers = match parse(&source) {
+        Ok(layers) => layers,
+        Err((Some(line), message)) => panic!("{KEYMAP}:{line}: {message}"),
+        Err((None, message)) => panic!("{KEYMAP}: {message}"),
+    };
+    fs::write(out.join("key_codes.rs"), generate(&layers)).unwrap();
+    fs::write(out.join("readme_keymap.md"), readme_table(&layers)).unwrap();
+}
+
+struct Layer {
+    name: String,
+    // 元の行(READMEにそのまま載せる)
+    lines: Vec<String>,
+    keys: Vec<Vec<&'static str>>,
+}
+
+// エラーの行番号(ファイル全体のエラーならNone)とメッセージ
+type ParseError = (Option<usize>, String);
+
+fn parse(source: &str) -> Result<Vec<Layer>, ParseError> {
+    let mut layers: Vec<Layer> = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let number = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            if let Some(layer) = layers.last() {
+                check_rows(layer).map_err(|message| (None, message))?;
+            }
+            if layers.iter().any(|layer| layer.name == name) {
+                return Err((Some(number), format!("layer [{name}] is defined twice")));
+            }
+            if !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+                return Err((Some(number), format!("invalid layer name [{name}]")));
+            }
+            layers.push(Layer {
+                name: name.to_string(),
+                lines: Vec::new(),
+                keys: Vec::new(),
+            });
+            continue;
+        }
+
+        let Some(layer) = layers.last_mut() else {
+            return Err((Some(number), "row before any [layer]".to_string()));
+        };
+        let row = layer.keys.len();
+        if row >= ROWS {
+            return Err((
+                Some(number),
+                format!("[{}] has more than {ROWS} rows", layer.name),
+            ));
+        }
+        let Some(cells) = line
+            .strip_prefix('|')
+            .and_then(|s| s.strip_suffix('|'))
+            .map(|s| s.split('|').collect::<Vec<_>>())
+        else {
+            return Err((
+                Some(number),
+                format!(
+                    "[{}] row {row}: a row must start and end with '|'",
+                    layer.name
+                ),
+            ));
+        };
+        if cells.len() != COLS {
+            return Err((
+                Some(number),
+                format!(
+                    "[{}] row {row}: expected {COLS} columns, found {}",
+                    layer.name,
+                    cells.len()
+                ),
+            ));
+        }
+        let mut keys = Vec::new();
+        for (col, cell) in cells.iter().enumerate() {
+            let name = cell.trim();
+            let Some(key) = key(name) else {
+                return Err((
+                    Some(number),
+                    format!(
+                        "[{}] row {row}, column {col}: unknown key name `{name}`",
+                        layer.name
+                    ),
+                ));
+            };
+            keys.push(key);
+        }
+        layer.lines.push(line.to_string());
+        layer.keys.push(keys);
+    }
+
+    match layers.last() {
+        Some(layer) => check_rows(layer).map_err(|message| (None, message))?,
+        None => return Err((None, "no layers".to_string())),
+    }
+    Ok(layers)
+}
+
+fn check_rows(layer: &Layer) -> Result<(), String> {
+    if layer.keys.len() == ROWS {
+        Ok(())
+    } else {
+        Err(format!(
+            "[{}] has {} rows, expected {ROWS}",
+            layer.name,
+            layer.keys.len()
+        ))
+    }
+}
+
+fn generate(layers: &[Layer]) -> String {
+    let mut code = format!("// {KEYMAP}からbuild.rsで生成している\n");
+    for layer in layers {
+        writeln!(
+            code,
+            "\npub const {}: [[Key; {COLS}]; {ROWS}] = [",
+            layer.name.to_uppercase()
+        )
+        .unwrap();
+        for row in &layer.keys {
+            let keys = row
+                .iter()
+                .map(|key| format!("Key::{key}"))
+                .collect::<Vec<_>>();
+            writeln!(code, "    [{}],", keys.join(", ")).unwrap();
+        }
+        code.push_str("];\n");
+    }
+    code
+}
+
+// READMEに載せる表(前後の行も含む)
+fn readme_table(layers: &[Layer]) -> String {
+    let mut table = format!("{README_BEGIN}\n```text\n");
+    for (i, layer) in layers.iter().enumerate() {
+        if i > 0 {
+            table.push('\n');
+        }
+        writeln!(table, "[{}]", layer.name).unwrap();
+        for line in &layer.lines {
+            writeln!(table, "{line}").unwrap();
+        }
+    }
+    table.push_str("```\n");
+    table.push_str(README_END);
+    table
+}
+
+// `layout!`と同じ名前
+fn key(name: &str) -> Option<&'static str> {
+    let key = match name {
+        "" => "None",
+        "Trn" => "Transparent",
+        "A" => "A",
+        "B" => "B",
+        "C" => "C",
+        "D" => "D",
+        "E" => "E",
+        "F" => "F",
+        "G" => "G",
+        "H" => "H",
+        "I" => "I",
+        "J" => "J",
+        "K" => "K",
+        "L" => "L",
+        "M" => "M",
+        "N" => "N",
+        "O" => "O",
+        "P" => "P",
+        "Q" => "Q",
+        "R" => "R",
+        "S" => "S",
+        "T" => "T",
+        "U" => "U",
+        "V" => "V",
+        "W" => "W",
+        "X" => "X",
+        "Y" => "Y",
+        "Z" => "Z",
+        "1" => "Digit1",
+        "2" => "Digit2",
+        "3" => "Digit3",
+        "4" => "Digit4",
+        "5" => "Digit5",
+        "6" => "Digit6",
+        "7" => "Digit7",
+        "8" => "Digit8",
+        "9" => "Digit9",
+        "0" => "Digit0",
+        "Esc" => "Escape",
+        "Tab" => "Tab",
+        "Enter" => "Enter",
+        "Space" => "Space",
+        "BS" => "Backspace",
+        "Del" => "Delete",
+        "-" => "Minus",
+        "=" => "Equal",
+        "[" => "LeftBracket",
+        "]" => "RightBracket",
+        "\\" => "Backslash",
+        ";" => "Semicolon",
+        "'" => "Quote",
+        "`" => "Grave",
+        "," => "Comma",
+        "." => "Period",
+        "/" => "Slash",
+        "!" => "Exclamation",
+        "@" => "At",
+        "#" => "Hash",
+        "$" => "Dollar",
+        "%" => "Percent",
+        "^" => "Caret",
+        "&" => "Ampersand",
+        "*" => "Asterisk",
+        "(" => "LeftParenthesis",
+        ")" => "RightParenthesis",
+        "_" => "Underscore",
+        "+" => "Plus",
+        "{" => "LeftCurlyBrace",
+        "}" => "RightCurlyBrace",
+        "Pipe" => "Pipe",
+        ":" => "Colon",
+        "\"" => "DoubleQuote",
+        "~" => "Tilde",
+        "<" => "LessThan",
+        ">" => "GreaterThan",
+        "?" => "Question",
+        "F1" => "F1",
+        "F2" => "F2",
+        "F3" => "F3",
+        "F4" => "F4",
+        "F5" => "F5",
+        "F6" => "F6",
+        "F7" => "F7",
+        "F8" => "F8",
+        "F9" => "F9",
+        "F10" => "F10",
+        "F11" => "F11",
+        "F12" => "F12",
+        "Up" => "Up",
+        "Down" => "Down",
+        "Left" => "Left",
+        "Right" => "Right",
+        "LCtl" => "LeftControl",
+        "LSft" => "LeftShift",
+        "LAlt" => "LeftAlt",
+        "LGui" => "LeftGui",
+        "RCtl" => "RightControl",
+        "RSft" => "RightShift",
+        "RAlt" => "RightAlt",
+        "RGui" => "RightGui",
+        "MVlDn" => "MediaVolumeDown",
+        "MMute" => "MediaMute",
+        "MVlUp" => "MediaVolumeUp",
+        "MPrev" => "MediaPrevTrack",
+        "MPlPs" => "MediaPlayPause",
+        "MNext" => "MediaNextTrack",
+        _ => return None,
+    };
+    Some(key)
 }
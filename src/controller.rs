@@ -0,0 +1,231 @@
+use fugit::MicrosDurationU32;
+use rp2040_hal::{rom_data, timer::Instant};
+use rustkbd::{
+    keyboard::{Key, KeySwitches as _},
+    Vec,
+};
+use usb_device::{bus::UsbBus, UsbError};
+
+use crate::{
+    gamepad::Gamepad,
+    layout::{Action, Keymap, Layer, Layout},
+    macros::MacroPlayer,
+    mouse::{Mouse, MouseKeys},
+    settings::Settings,
+    switches::{AnalogSampler, Filter, KeyMatrix, SwitchIdentifier},
+    usb::{GamepadReport, UsbCommunicator, KEYMAP_REPORT_SIZE},
+};
+
+// ホストからキーマップを読み書きするリクエストの先頭のバイト
+const KEYMAP_INFO: u8 = 0x01;
+const KEYMAP_GET: u8 = 0x02;
+const KEYMAP_SET: u8 = 0x03;
+const KEYMAP_SAVE: u8 = 0x04;
+const KEYMAP_RESET: u8 = 0x05;
+const KEYMAP_ERROR: u8 = 0xff;
+
+pub struct Controller<'a, B: UsbBus, S: AnalogSampler, F: Filter> {
+    pub communicator: UsbCommunicator<'a, B>,
+    pub key_switches: KeyMatrix<S, F, 4, 12>,
+    pub layout: Layout,
+    settings: Settings,
+    settings_changed: bool,
+    gamepad: Option<Gamepad>,
+    keys: Vec<Key, 12>,
+    gamepad_report: GamepadReport,
+    mouse: Mouse,
+    // 押した瞬間だけ効くアクションのために前回のアクションを覚えておく
+    actions: Vec<Action, 16>,
+    macro_player: Option<MacroPlayer>,
+    show_diagnostics: bool,
+    sensor_stream_interval: MicrosDurationU32,
+    // 最後にセンサーの値を送った時刻
+    sensor_stream_sent_at: Option<Instant>,
+}
+
+impl<'a, B: UsbBus, S: AnalogSampler, F: Filter> Controller<'a, B, S, F> {
+    pub fn new(
+        communicator: UsbCommunicator<'a, B>,
+        key_switches: KeyMatrix<S, F, 4, 12>,
+        mut layout: Layout,
+        settings: Settings,
+        gamepad: Gamepad,
+        mouse_keys: MouseKeys,
+        sensor_stream_interval: MicrosDurationU32,
+    ) -> Controller<'a, B, S, F> {
+        layout.set_base_layout(settings.base_layout);
+        Controller {
+            communicator,
+            key_switches,
+            layout,
+            settings,
+            settings_changed: false,
+            gamepad: settings.gamepad.then_some(gamepad),
+            keys: Vec::new(),
+            gamepad_report: GamepadReport::default(),
+            mouse: Mouse::new(mouse_keys),
+            actions: Vec::new(),
+            macro_player: None,
+            show_diagnostics: false,
+            sensor_stream_interval,
+            sensor_stream_sent_at: None,
+        }
+    }
+
+    pub fn main_loop(&mut self, now: Instant) {
+        if let Some(request) = self.communicator.take_keymap_request() {
+            let response = self.keymap_response(&request);
+            if let Err(e) = self.communicator.send_keymap_response(&response) {
+                defmt::warn!("UsbError: {}", defmt::Debug2Format(&e));
+            }
+        }
+
+        let mut switches = self.key_switches.scan();
+        // 軸に割り当てたキーは文字を送らない
+        if let Some(gamepad) = self.gamepad.as_ref() {
+            switches.retain(|switch| !gamepad.uses(switch));
+        }
+
+        let depths = self.key_switches.depths();
+        let mut keys = Vec::new();
+        let actions = self.layout.update(&switches, &depths, now);
+        for action in &actions {
+            let pressed = !self.actions.contains(action);
+            match *action {
+                Action::Key(key) => {
+                    keys.push(key).ok();
+                }
+                Action::Shifted(key) => {
+                    keys.push(Key::LeftShift).ok();
+                    keys.push(key).ok();
+                }
+                Action::ToggleGamepad if pressed => {
+                    self.settings.gamepad = !self.settings.gamepad;
+                    self.settings_changed = true;
+                }
+                Action::Bootloader => rom_data::reset_to_usb_boot(0, 0),
+                // 再生中のマクロがあれば無視する(キーマップで編集した知らない添字も)
+                Action::Macro(index) if pressed && self.macro_player.is_none() => {
+                    self.macro_player = Layout::MACROS
+                        .get(index as usize)
+                        .map(|steps| MacroPlayer::new(steps));
+                }
+                Action::Unicode(c) if pressed && self.macro_player.is_none() => {
+                    let steps = self.settings.unicode_input.steps(c);
+                    self.macro_player = Some(MacroPlayer::from_steps(steps));
+                }
+                Action::CycleBaseLayout if pressed => {
+                    self.settings.base_layout = self.settings.base_layout.next();
+                    self.layout.set_base_layout(self.settings.base_layout);
+                    self.settings_changed = true;
+                }
+                Action::CycleUnicodeInput if pressed => {
+                    self.settings.unicode_input = self.settings.unicode_input.next();
+                    self.settings_changed = true;
+                }
+                Action::ToggleDiagnostics if pressed => {
+                    self.show_diagnostics = !self.show_diagnostics;
+                }
+                // レイヤーのアクションはLayoutの中で済んでいる
+                _ => {}
+            }
+        }
+        self.actions = actions;
+        self.keys = keys;
+        self.mouse.update(&self.layout.mouse_actions(&depths), now);
+
+        if let Some(gamepad) = self.gamepad.as_ref() {
+            self.gamepad_report = gamepad.report(&depths);
+        }
+    }
+
+    /// 読み書きしたエントリを返す(できなければ`KEYMAP_ERROR`とリクエストの先頭)
+    fn keymap_response(&mut self, request: &[u8; KEYMAP_REPORT_SIZE]) -> [u8; KEYMAP_REPORT_SIZE] {
+        let mut response = [0; KEYMAP_REPORT_SIZE];
+        response[0] = request[0];
+        let [_, layer, row, col, ..] = *request;
+        let entry = Layer::ALL
+            .get(layer as usize)
+            .filter(|_| row < 4 && col < 12)
+            .map(|layer| (*layer, SwitchIdentifier { row, col }));
+        let ok = match (request[0], entry) {
+            (KEYMAP_INFO, _) => {
+                response[1..5].copy_from_slice(&[Keymap::VERSION, Layer::COUNT as u8, 4, 12]);
+                true
+            }
+            (KEYMAP_GET, Some((layer, switch))) => {
+                response[1..4].copy_from_slice(&request[1..4]);
+                self.layout
+                    .keymap_entry(layer, &switch)
+                    .to_bytes()
+                    .map(|bytes| response[4..9].copy_from_slice(&bytes))
+                    .is_some()
+            }
+            (KEYMAP_SET, Some((layer, switch))) => {
+                response[1..9].copy_from_slice(&request[1..9]);
+                request[4..9]
+                    .try_into()
+                    .ok()
+                    .and_then(Action::from_bytes)
+                    .map(|action| self.layout.set_keymap_entry(layer, &switch, action))
+                    .is_some()
+            }
+            (KEYMAP_SAVE, _) => {
+                self.layout.save_keymap();
+                true
+            }
+            (KEYMAP_RESET, _) => {
+                self.layout.reset_keymap();
+                true
+            }
+            _ => false,
+        };
+        if !ok {
+            response = [0; KEYMAP_REPORT_SIZE];
+            response[..2].copy_from_slice(&[KEYMAP_ERROR, request[0]]);
+        }
+        response
+    }
+
+    /// マクロの再生中は押しているキーの代わりにマクロのレポートを送る
+    pub fn send_keys(&mut self, now: Instant) -> Result<(), UsbError> {
+        if let Some(player) = self.macro_player.as_mut() {
+            match self.communicator.write_keys(player.keys()) {
+                Ok(()) => {
+                    if !player.advance(now) {
+                        self.macro_player = None;
+                    }
+                }
+                Err(UsbError::WouldBlock) => {}
+                Err(e) => return Err(e),
+            }
+        } else {
+            self.communicator.send_keys(&self.keys)?;
+        }
+        if let Some(report) = self.mouse.report() {
+            match self.communicator.write_mouse(&report) {
+                Ok(()) => self.mouse.sent(),
+                Err(UsbError::WouldBlock) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !self
+            .sensor_stream_sent_at
+            .is_some_and(|sent_at| now - sent_at < self.sensor_stream_interval)
+        {
+            self.sensor_stream_sent_at = Some(now);
+            self.communicator
+                .write_sensor_values(&self.key_switches.raw_values(), &self.key_switches.values());
+        }
+        self.communicator.send_gamepad(&self.gamepad_report)
+    }
+
+    pub fn shows_diagnostics(&self) -> bool {
+        self.show_diagnostics
+    }
+
+    /// 変更された設定を一度だけ返す(Flashへの保存用)
+    pub fn take_changed_settings(&mut self) -> Option<Settings> {
+        core::mem::take(&mut self.settings_changed).then_some(self.settings)
+    }
+}
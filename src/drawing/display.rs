@@ -3,9 +3,11 @@ use core::ops::Deref;
 use embedded_graphics::{
     draw_target::DrawTarget as _,
     image::{Image, ImageRaw},
+    mono_font::{ascii::FONT_6X10, MonoTextStyle},
     pixelcolor::BinaryColor,
-    prelude::Point,
-    primitives::{Line, PrimitiveStyle, StyledDrawable},
+    prelude::{Pixel, Point, Size},
+    primitives::{Line, PrimitiveStyle, Rectangle, StyledDrawable},
+    text::{Baseline, Text},
     Drawable,
 };
 use rp2040_hal::{pac::i2c0::RegisterBlock, I2C};
@@ -17,6 +19,8 @@ use ssd1306::{
     I2CDisplayInterface, Ssd1306,
 };
 
+use crate::{layout::BaseLayout, switches::Health};
+
 pub struct Display<I: Deref<Target = RegisterBlock>, J> {
     cats: [ImageRaw<'static, BinaryColor>; 4],
     display: Ssd1306<
@@ -44,7 +48,12 @@ impl<I: Deref<Target = RegisterBlock>, J> Display<I, J> {
         }
     }
 
-    pub fn draw(&mut self, values: &[[u16; 12]; 4]) {
+    pub fn draw(
+        &mut self,
+        values: &[[u16; 12]; 4],
+        thresholds: &[[u16; 12]; 4],
+        base_layout: BaseLayout,
+    ) {
         self.display.clear(BinaryColor::Off).ok();
 
         // cat
@@ -53,8 +62,8 @@ impl<I: Deref<Target = RegisterBlock>, J> Display<I, J> {
         image.draw(&mut self.display).ok();
 
         // chart
-        for (i, row) in values.iter().enumerate() {
-            for (j, v) in row.iter().enumerate() {
+        for (i, (row, thresholds)) in values.iter().zip(thresholds).enumerate() {
+            for (j, (v, t)) in row.iter().zip(thresholds).enumerate() {
                 let k = (i * 12 + j) as i32;
                 let v = *v as i32 / 5 + 4;
                 Line::new(Point::new(64 + k, 32), Point::new(64 + k, 32 - v))
@@ -63,21 +72,73 @@ impl<I: Deref<Target = RegisterBlock>, J> Display<I, J> {
                         &mut self.display,
                     )
                     .ok();
+
+                // threshold (バーに重なるときは反転させる)
+                let t = *t as i32 / 5 + 4;
+                let color = if v >= t {
+                    BinaryColor::Off
+                } else {
+                    BinaryColor::On
+                };
+                Pixel(Point::new(64 + k, 32 - t), color)
+                    .draw(&mut self.display)
+                    .ok();
             }
         }
-        // threshold
-        let v = 40 / 5 + 4;
-        Line::new(Point::new(64, 32 - v), Point::new(128, 32 - v))
-            .draw_styled(
-                &PrimitiveStyle::with_stroke(BinaryColor::On, 1),
-                &mut self.display,
-            )
-            .ok();
+
+        // 配列(グラフの右の空いているところ)
+        let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+        Text::with_baseline(
+            base_layout.label(),
+            Point::new(116, 0),
+            style,
+            Baseline::Top,
+        )
+        .draw(&mut self.display)
+        .ok();
 
         self.display.flush().ok();
         self.frame += 1;
     }
 
+    /// 異常のあるキーを塗りつぶして表示する
+    pub fn draw_diagnostics(&mut self, health: &[[Health; 12]; 4]) {
+        self.display.clear(BinaryColor::Off).ok();
+
+        let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+        let labels = [
+            ("STUCK", health.iter().flatten().any(|h| h.stuck)),
+            ("OPEN", health.iter().flatten().any(|h| h.open)),
+            ("NOISY", health.iter().flatten().any(|h| h.noisy)),
+        ];
+        for (i, (label, _)) in labels.iter().filter(|(_, any)| *any).enumerate() {
+            Text::with_baseline(label, Point::new(0, i as i32 * 10), style, Baseline::Top)
+                .draw(&mut self.display)
+                .ok();
+        }
+
+        // 1キーあたり5x8
+        for (i, row) in health.iter().enumerate() {
+            for (j, h) in row.iter().enumerate() {
+                let origin = Point::new(64 + j as i32 * 5, i as i32 * 8);
+                if h.is_ok() {
+                    Pixel(origin + Point::new(2, 4), BinaryColor::On)
+                        .draw(&mut self.display)
+                        .ok();
+                } else {
+                    Rectangle::new(origin, Size::new(4, 7))
+                        .draw_styled(
+                            &PrimitiveStyle::with_fill(BinaryColor::On),
+                            &mut self.display,
+                        )
+                        .ok();
+                }
+            }
+        }
+
+        self.display.flush().ok();
+    }
+
     pub fn draw_sleep(&mut self) {
         self.display.clear(BinaryColor::Off).ok();
         self.display.flush().ok();
@@ -0,0 +1,131 @@
+use crate::{switches::SwitchIdentifier, usb::GamepadReport};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    X,
+    Y,
+    Rx,
+    Ry,
+}
+
+/// 押し込み量から軸の値への応答曲線
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Curve {
+    Linear,
+    Quadratic,
+    Cubic,
+}
+
+impl Curve {
+    pub fn apply(&self, x: f32) -> f32 {
+        match self {
+            Curve::Linear => x,
+            Curve::Quadratic => x * x,
+            Curve::Cubic => x * x * x,
+        }
+    }
+}
+
+pub struct AxisKey {
+    pub switch: SwitchIdentifier,
+    pub axis: Axis,
+    pub negative: bool,
+}
+
+pub struct Gamepad {
+    pub keys: &'static [AxisKey],
+    /// これより浅い押し込み(0.0〜1.0)は0とみなす
+    pub dead_zone: f32,
+    /// これより深い押し込みは最大とみなす
+    pub saturation: f32,
+    pub curve: Curve,
+}
+
+impl Gamepad {
+    pub fn uses(&self, switch: &SwitchIdentifier) -> bool {
+        self.keys.iter().any(|key| key.switch == *switch)
+    }
+
+    /// `depths`は`KeyMatrix::depths`
+    pub fn report(&self, depths: &[[f32; 12]; 4]) -> GamepadReport {
+        let mut axes = [0.0_f32; 4];
+        for key in self.keys {
+            let depth = depths[key.switch.row as usize][key.switch.col as usize];
+            let value =
+                ((depth - self.dead_zone) / (self.saturation - self.dead_zone)).clamp(0.0, 1.0);
+            let value = self.curve.apply(value);
+            axes[key.axis as usize] += if key.negative { -value } else { value };
+        }
+
+        let axis = |axis: Axis| (axes[axis as usize].clamp(-1.0, 1.0) * 127.0) as i8;
+        GamepadReport {
+            x: axis(Axis::X),
+            y: axis(Axis::Y),
+            rx: axis(Axis::Rx),
+            ry: axis(Axis::Ry),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // WとSでY軸
+    const W: SwitchIdentifier = SwitchIdentifier { row: 0, col: 2 };
+    const S: SwitchIdentifier = SwitchIdentifier { row: 1, col: 2 };
+    const KEYS: [AxisKey; 2] = [
+        AxisKey {
+            switch: W,
+            axis: Axis::Y,
+            negative: true,
+        },
+        AxisKey {
+            switch: S,
+            axis: Axis::Y,
+            negative: false,
+        },
+    ];
+
+    fn y(curve: Curve, w: f32, s: f32) -> i8 {
+        let gamepad = Gamepad {
+            keys: &KEYS,
+            dead_zone: 0.1,
+            saturation: 0.9,
+            curve,
+        };
+        let mut depths = [[0.0; 12]; 4];
+        depths[W.row as usize][W.col as usize] = w;
+        depths[S.row as usize][S.col as usize] = s;
+        let report = gamepad.report(&depths);
+        assert_eq!((report.x, report.rx, report.ry), (0, 0, 0));
+        report.y
+    }
+
+    #[test]
+    fn ignores_the_dead_zone() {
+        assert_eq!(y(Curve::Linear, 0.1, 0.0), 0);
+        assert_eq!(y(Curve::Linear, 0.0, 0.05), 0);
+    }
+
+    #[test]
+    fn saturates_at_full_scale() {
+        assert_eq!(y(Curve::Linear, 0.0, 0.9), 127);
+        assert_eq!(y(Curve::Linear, 0.0, 1.0), 127);
+        assert_eq!(y(Curve::Linear, 1.0, 0.0), -127);
+    }
+
+    #[test]
+    fn shapes_the_middle_by_curve() {
+        // 0.5は範囲のちょうど真ん中
+        assert_eq!(y(Curve::Linear, 0.0, 0.5), 63);
+        assert_eq!(y(Curve::Quadratic, 0.0, 0.5), 31);
+        assert_eq!(y(Curve::Cubic, 0.0, 0.5), 15);
+    }
+
+    #[test]
+    fn cancels_opposing_keys() {
+        assert_eq!(y(Curve::Linear, 1.0, 1.0), 0);
+        assert_eq!(y(Curve::Linear, 0.5, 0.9), 63);
+    }
+}
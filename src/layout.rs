@@ -1,16 +1,110 @@
-use rustkbd::keyboard::{self, layout, Key};
+mod action;
+mod auto_shift;
+mod base_layout;
+mod caps_word;
+mod combo;
+mod dual_stage;
+mod encoding;
+mod key_codes;
+mod key_override;
+mod keymap;
+mod leader;
+mod tap_dance;
+mod tap_hold;
 
-use crate::switches::SwitchIdentifier;
+use fugit::MicrosDurationU32;
+use rp2040_hal::timer::Instant;
+use rustkbd::{keyboard::Key, Vec};
 
-#[derive(Debug, Clone, Default)]
-#[non_exhaustive]
-pub struct Layout {}
+use crate::{macros::MacroStep, mouse::MouseAction, switches::SwitchIdentifier};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, keyboard::Layer)]
+pub use action::Action;
+pub use auto_shift::AutoShift;
+pub use base_layout::BaseLayout;
+pub use combo::Combo;
+pub use dual_stage::DualStage;
+pub use key_override::KeyOverride;
+pub use keymap::Keymap;
+pub use leader::LeaderSequence;
+pub use tap_dance::{TapDance, TapDanceAction};
+pub use tap_hold::{Hold, TapHold, TapHoldConfig, TapHoldMode};
+
+use auto_shift::PendingAutoShift;
+use caps_word::caps_word_action;
+use combo::ComboState;
+use leader::{Match, PendingLeader};
+use tap_dance::PendingTapDance;
+use tap_hold::PendingTapHold;
+
+/// アクションを起こしたもの
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Trigger {
+    Switch(SwitchIdentifier),
+    /// `Layout::COMBOS`の添字
+    Combo(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Event {
+    Pressed(Trigger),
+    Released(Trigger),
+}
+
+/// 押したときに決まったアクション(離すまで変わらない)
+#[derive(Debug, Clone, Copy)]
+struct ActiveAction {
+    trigger: Trigger,
+    action: Action,
+    pressed_at: Instant,
+    // DualStageを深く押しているか
+    deep: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Layout {
+    keymap: Keymap,
+    keymap_save_requested: bool,
+    tap_hold: TapHoldConfig,
+    // 前回押されていたスイッチ
+    switches: Vec<SwitchIdentifier, 12>,
+    combo: ComboState,
+    active: Vec<ActiveAction, 12>,
+    pending: Option<PendingTapHold>,
+    tap_dance: Option<PendingTapDance>,
+    leader: Option<PendingLeader>,
+    auto_shift: AutoShift,
+    auto_shift_enabled: bool,
+    auto_shift_pending: Option<PendingAutoShift>,
+    caps_word: bool,
+    base_layout: BaseLayout,
+    // 一瞬だけ有効にするアクションと、それを終える時刻
+    taps: Vec<(Action, Instant), 8>,
+    default_layer: Layer,
+    toggled_layers: [bool; Layer::COUNT],
+    locked_layer: Option<Layer>,
+    one_shot_layer: Option<Layer>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Layer {
     Default,
     Lower,
     Raise,
+    /// LowerとRaiseを同時に押したとき
+    Adjust,
+    /// マウスキー(Adjust + Gでオン・オフ)
+    Mouse,
+}
+
+impl Layer {
+    pub const ALL: [Layer; 5] = [
+        Layer::Default,
+        Layer::Lower,
+        Layer::Raise,
+        Layer::Adjust,
+        Layer::Mouse,
+    ];
+    pub const COUNT: usize = Self::ALL.len();
 }
 
 impl Default for Layer {
@@ -20,53 +114,826 @@ impl Default for Layer {
 }
 
 impl Layout {
-    const KEY_CODES_DEFAULT: [[Key; 12]; 4] = layout! {r"
-        | Esc |  Q  |  W  |  E  |  R  |  T  |  Y  |  U  |  I  |  O  |  P  | Del |
-        | LCtl|  A  |  S  |  D  |  F  |  G  |  H  |  J  |  K  |  L  |  ;  |  '  |
-        | LSft|  Z  |  X  |  C  |  V  |  B  |  N  |  M  |  ,  |  .  |  /  |Enter|
-        |     |     |     | LAlt| LGui|Space|     |     |     |     |     |     |
-    "};
-    const KEY_CODES_LOWER: [[Key; 12]; 4] = layout! {r"
-        | Trn |  1  |  2  |  3  |  4  |  5  |  6  |  7  |  8  |  9  |  0  | Tab |
-        | Trn |  !  |  @  |  (  |  )  |  *  |  -  |  =  |  [  |  ]  | Pipe|  `  |
-        | Trn |  %  |  ^  |  #  |  $  |  &  |  _  |  +  |  {  |  }  |  \  |  ~  |
-        |     |     |     | Trn | Trn | Trn |     |     |     |     |     |     |
-    "};
-    const KEY_CODES_RAISE: [[Key; 12]; 4] = layout! {r"
-        | Trn |     |     |     |     |     |     |     |     |MVlDn|MMute|MVlUp|
-        | Trn |     |     |     |     |     |     |     |     |     |  Up |     |
-        | Trn |     |     |     |     |     |MPrev|MPlPs|MNext| Left| Down|Right|
-        |     |     |     | Trn | Trn | Trn |     |     |     |     |     |     |
-    "};
-}
+    // キーコードはkeymap.txtの表(build.rsで生成する)にアクションを重ねる
+    const ACTIONS_DEFAULT: [[Action; 12]; 4] =
+        Self::with_base_keys(Action::from_keys(key_codes::DEFAULT));
+    // QWERTY以外のDefaultレイヤー(キーマップで編集していないキーだけ置き換える)
+    const ACTIONS_COLEMAK: [[Action; 12]; 4] =
+        Self::with_base_keys(Action::from_keys(key_codes::COLEMAK));
+    const ACTIONS_DVORAK: [[Action; 12]; 4] =
+        Self::with_base_keys(Action::from_keys(key_codes::DVORAK));
+    const ACTIONS_LOWER: [[Action; 12]; 4] =
+        Self::with_layer_keys(Action::from_keys(key_codes::LOWER));
+    const ACTIONS_RAISE: [[Action; 12]; 4] = {
+        let mut actions = Self::with_layer_keys(Action::from_keys(key_codes::RAISE));
+        // Raise + Q
+        actions[0][1] = Action::LayerLock;
+        // Raise + A
+        actions[1][1] = Action::Leader;
+        // Raise + W: ゲームで浅く押すと歩き、深く押すとShiftも押して走る
+        actions[0][2] = Action::DualStage(DualStage {
+            shallow: Key::W,
+            deep: Key::LeftShift,
+            depth: 80,
+            keep_shallow: true,
+        });
+        actions
+    };
+    const ACTIONS_ADJUST: [[Action; 12]; 4] = {
+        let mut actions = Self::with_layer_keys(Action::from_keys(key_codes::ADJUST));
+        // Adjust + Q
+        actions[0][1] = Action::ToggleGamepad;
+        // Adjust + Del
+        actions[0][11] = Action::Bootloader;
+        // Adjust + T
+        actions[0][5] = Action::Macro(1);
+        // Adjust + A
+        actions[1][1] = Action::Macro(0);
+        // Adjust + S
+        actions[1][2] = Action::ToggleAutoShift;
+        // Adjust + D: Lowerを何も押していないときのレイヤーにする(Adjust + Fで戻す)
+        actions[1][3] = Action::DefaultLayer(Layer::Lower);
+        // Adjust + F
+        actions[1][4] = Action::DefaultLayer(Layer::Default);
+        // Adjust + X: 次のキーだけMouseレイヤー(クリックなど)
+        actions[2][2] = Action::OneShotLayer(Layer::Mouse);
+        // Adjust + U
+        actions[0][7] = Action::CycleUnicodeInput;
+        // Adjust + C
+        actions[2][3] = Action::CapsWord;
+        // Adjust + B
+        actions[2][5] = Action::CycleBaseLayout;
+        // Adjust + G
+        actions[1][5] = Action::ToggleLayer(Layer::Mouse);
+        // Adjust + H
+        actions[1][6] = Action::ToggleDiagnostics;
+        actions
+    };
+    // 右手でカーソルとホイール、左手でボタン
+    const ACTIONS_MOUSE: [[Action; 12]; 4] = {
+        let mut actions = Self::with_layer_keys(Action::from_keys(key_codes::MOUSE));
+        // Esc
+        actions[0][0] = Action::ToggleLayer(Layer::Mouse);
+        actions[0][6] = Action::Mouse(MouseAction::WheelLeft);
+        actions[0][7] = Action::Mouse(MouseAction::WheelUp);
+        actions[0][8] = Action::Mouse(MouseAction::Up);
+        actions[0][9] = Action::Mouse(MouseAction::WheelRight);
+        actions[1][2] = Action::Mouse(MouseAction::Button3);
+        actions[1][3] = Action::Mouse(MouseAction::Button2);
+        actions[1][4] = Action::Mouse(MouseAction::Button1);
+        actions[1][7] = Action::Mouse(MouseAction::Left);
+        actions[1][8] = Action::Mouse(MouseAction::Down);
+        actions[1][9] = Action::Mouse(MouseAction::Right);
+        actions[2][7] = Action::Mouse(MouseAction::WheelDown);
+        actions
+    };
+
+    // (X, Y, Z): XとYのレイヤーキーを同時に押しているとZになる
+    const TRI_LAYERS: [(Layer, Layer, Layer); 1] = [(Layer::Lower, Layer::Raise, Layer::Adjust)];
+
+    // どのレイヤーでも、キーが決まったあとに置き換える
+    const KEY_OVERRIDES: [KeyOverride; 2] = [
+        // Shift + DelでBackspace
+        KeyOverride {
+            modifier: Key::LeftShift,
+            key: Key::Delete,
+            replacement: Key::Backspace,
+        },
+        // Shift + ,で;
+        KeyOverride {
+            modifier: Key::LeftShift,
+            key: Key::Comma,
+            replacement: Key::Semicolon,
+        },
+    ];
+
+    /// Flashにキーマップが保存されていないときのキーマップ
+    pub const DEFAULT_KEYMAP: Keymap = Keymap::new([
+        Self::ACTIONS_DEFAULT,
+        Self::ACTIONS_LOWER,
+        Self::ACTIONS_RAISE,
+        Self::ACTIONS_ADJUST,
+        Self::ACTIONS_MOUSE,
+    ]);
+
+    const COMBOS: [Combo; 1] = [
+        // J + K
+        Combo {
+            switches: &[
+                SwitchIdentifier { row: 1, col: 7 },
+                SwitchIdentifier { row: 1, col: 8 },
+            ],
+            action: Action::Key(Key::Escape),
+            layers: Some(&[Layer::Default]),
+        },
+    ];
+    pub const MACROS: [&'static [MacroStep]; 2] = [
+        // macOSで範囲を選んでスクリーンショット(Cmd + Shift + 4)
+        &[
+            MacroStep::Press(Key::LeftGui),
+            MacroStep::Press(Key::LeftShift),
+            MacroStep::Tap(Key::Digit4),
+            MacroStep::Release(Key::LeftShift),
+            MacroStep::Release(Key::LeftGui),
+        ],
+        // macOSのSpotlightでターミナルを開く(Spotlightが開くのを待ってから打つ)
+        &[
+            MacroStep::Press(Key::LeftGui),
+            MacroStep::Tap(Key::Space),
+            MacroStep::Release(Key::LeftGui),
+            MacroStep::Delay(200),
+            MacroStep::Text("terminal\n"),
+        ],
+    ];
+
+    pub const TAP_DANCES: [TapDance; 1] = [
+        // 1回で'、2回で"、押し続けるとRaise
+        TapDance {
+            actions: &[
+                TapDanceAction {
+                    tap: Key::Quote,
+                    hold: Some(Hold::Layer(Layer::Raise)),
+                },
+                TapDanceAction {
+                    tap: Key::DoubleQuote,
+                    hold: None,
+                },
+            ],
+        },
+    ];
+
+    const LEADER_SEQUENCES: [LeaderSequence; 6] = [
+        LeaderSequence {
+            keys: &[Key::B, Key::O, Key::O, Key::T],
+            action: Action::Bootloader,
+        },
+        LeaderSequence {
+            keys: &[Key::G, Key::P],
+            action: Action::ToggleGamepad,
+        },
+        LeaderSequence {
+            keys: &[Key::L, Key::R],
+            action: Action::ToggleLayer(Layer::Raise),
+        },
+        LeaderSequence {
+            keys: &[Key::S, Key::S],
+            action: Action::Macro(0),
+        },
+        LeaderSequence {
+            keys: &[Key::E, Key::M],
+            action: Action::Unicode('—'),
+        },
+        LeaderSequence {
+            keys: &[Key::R, Key::A],
+            action: Action::Unicode('→'),
+        },
+    ];
+    // リーダーキーのあと、次のキーをこの時間内に押さないと取り消す
+    const LEADER_TIMEOUT: MicrosDurationU32 = MicrosDurationU32::millis(1000);
+
+    // コンボのスイッチはこの時間内に全部押す
+    const COMBO_TERM: MicrosDurationU32 = MicrosDurationU32::millis(50);
+
+    // タップしたキーはUSBのレポートで拾われるようにしばらく押したままにする(Controllerも押した瞬間を拾える)
+    const TAP_DURATION: MicrosDurationU32 = MicrosDurationU32::millis(20);
+
+    // どの配列でも'はタップダンスにする
+    const fn with_base_keys(actions: [[Action; 12]; 4]) -> [[Action; 12]; 4] {
+        let mut actions = Self::with_layer_keys(actions);
+        let mut row = 0;
+        while row < 4 {
+            let mut col = 0;
+            while col < 12 {
+                if matches!(actions[row][col], Action::Key(Key::Quote)) {
+                    actions[row][col] = Action::TapDance(0);
+                }
+                col += 1;
+            }
+            row += 1;
+        }
+        actions
+    }
+
+    // 右手の親指はどのレイヤーでもLower(タップでSpace)とRaise
+    const fn with_layer_keys(mut actions: [[Action; 12]; 4]) -> [[Action; 12]; 4] {
+        actions[3][7] = Action::TapHold(TapHold {
+            tap: Key::Space,
+            hold: Hold::Layer(Layer::Lower),
+        });
+        actions[3][8] = Action::Layer(Layer::Raise);
+        actions
+    }
+
+    /// オートシフトは`Action::ToggleAutoShift`で有効にする
+    pub fn new(keymap: Keymap, tap_hold: TapHoldConfig, auto_shift: AutoShift) -> Layout {
+        Layout {
+            keymap,
+            keymap_save_requested: false,
+            tap_hold,
+            switches: Vec::new(),
+            combo: ComboState::default(),
+            active: Vec::new(),
+            pending: None,
+            tap_dance: None,
+            leader: None,
+            auto_shift,
+            auto_shift_enabled: false,
+            auto_shift_pending: None,
+            caps_word: false,
+            base_layout: BaseLayout::Qwerty,
+            taps: Vec::new(),
+            default_layer: Layer::Default,
+            toggled_layers: [false; Layer::COUNT],
+            locked_layer: None,
+            one_shot_layer: None,
+        }
+    }
+
+    /// 押されているスイッチから、いま有効なアクションを返す(スキャンごとに呼ぶ)
+    ///
+    /// `depths`はキーごとの押し込み量(オートシフトと`DualStage`に使う)
+    pub fn update(
+        &mut self,
+        switches: &[SwitchIdentifier],
+        depths: &[[f32; 12]; 4],
+        now: Instant,
+    ) -> Vec<Action, 16> {
+        // 離したスイッチ、押したスイッチの順にイベントにする
+        let mut events = Vec::<Event, 24>::new();
+        for switch in &self.switches {
+            if !switches.contains(switch) {
+                events.push(Event::Released(Trigger::Switch(*switch))).ok();
+            }
+        }
+        for switch in switches {
+            if !self.switches.contains(switch) {
+                events.push(Event::Pressed(Trigger::Switch(*switch))).ok();
+            }
+        }
+        self.switches = switches.iter().copied().take(12).collect();
+        for event in events {
+            let events = self.combo.handle(&Self::COMBOS, event, self.layer(), now);
+            self.process(&events, now);
+        }
+        let events = self.combo.expire(now, Self::COMBO_TERM);
+        self.process(&events, now);
+
+        if self
+            .pending
+            .as_ref()
+            .is_some_and(|pending| pending.is_expired(now, &self.tap_hold))
+        {
+            let events = self.resolve_tap_hold(true, now);
+            self.process(&events, now);
+        }
+        if self
+            .tap_dance
+            .is_some_and(|dance| now - dance.changed_at >= self.tap_hold.tapping_term)
+        {
+            self.resolve_tap_dance(false, now);
+        }
+
+        if self
+            .leader
+            .as_ref()
+            .is_some_and(|leader| now - leader.changed_at >= Self::LEADER_TIMEOUT)
+        {
+            self.leader = None;
+        }
+
+        if let Some(pending) = self.auto_shift_pending {
+            if pending.should_shift(self.auto_shift, depths, now) {
+                self.auto_shift_pending = None;
+                self.push_active(
+                    Trigger::Switch(pending.switch),
+                    Action::Shifted(pending.key),
+                    pending.pressed_at,
+                );
+            }
+        }
 
-impl rustkbd::keyboard::Layout<2> for Layout {
-    type Identifier = SwitchIdentifier;
-    type Layer = Layer;
+        self.taps.retain(|(_, until)| *until > now);
+        let mut actions = Vec::new();
+        for (action, _) in &self.taps {
+            actions.push(*action).ok();
+        }
+        for active in self.active.iter_mut() {
+            let Action::DualStage(dual_stage) = active.action else {
+                actions.push(active.action).ok();
+                continue;
+            };
+            // コンボには深さがないので浅い方だけ
+            if let Trigger::Switch(switch) = active.trigger {
+                let depth = depths[switch.row as usize][switch.col as usize];
+                active.deep = dual_stage.is_deep(depth, active.deep);
+            }
+            for key in dual_stage.keys(active.deep) {
+                actions.push(Action::Key(key)).ok();
+            }
+        }
+        key_override::apply(&Self::KEY_OVERRIDES, &mut actions);
+        actions
+    }
 
-    fn layer(&self, switches: &[Self::Identifier]) -> Layer {
-        switches
+    /// 有効なレイヤーのうち一番上のレイヤー(`TRI_LAYERS`も含む)
+    pub fn layer(&self) -> Layer {
+        let tri_layers = Self::TRI_LAYERS
             .iter()
-            .map(|switch| match switch {
-                SwitchIdentifier { row: 3, col: 7 } => Layer::Lower,
-                SwitchIdentifier { row: 3, col: 8 } => Layer::Raise,
-                _ => Layer::Default,
-            })
+            .filter(|(x, y, _)| self.is_layer_on(*x) && self.is_layer_on(*y))
+            .map(|(_, _, z)| *z);
+        Layer::ALL
+            .into_iter()
+            .filter(|layer| self.is_layer_on(*layer))
+            .chain(tri_layers)
             .max()
             .unwrap_or_default()
     }
 
-    fn key(&self, layer: Layer, switch: &Self::Identifier) -> Key {
-        match (layer, *switch) {
-            (Layer::Default, SwitchIdentifier { row, col }) => {
-                Self::KEY_CODES_DEFAULT[row as usize][col as usize]
+    fn is_layer_on(&self, layer: Layer) -> bool {
+        layer == self.default_layer
+            || self.toggled_layers[layer as usize]
+            || self.locked_layer == Some(layer)
+            || self.one_shot_layer == Some(layer)
+            || self.active.iter().any(|active| {
+                matches!(active.action, Action::Layer(l) | Action::OneShotLayer(l) if l == layer)
+            })
+    }
+
+    // レイヤーやモードを変えるアクションは押したときに効く
+    fn apply_layout_action(&mut self, action: Action) {
+        match action {
+            Action::ToggleLayer(layer) => self.toggled_layers[layer as usize] ^= true,
+            Action::LayerLock => {
+                self.locked_layer = match self.locked_layer {
+                    Some(_) => None,
+                    None => Some(self.layer()),
+                }
             }
-            (Layer::Lower, SwitchIdentifier { row, col }) => {
-                Self::KEY_CODES_LOWER[row as usize][col as usize]
+            Action::DefaultLayer(layer) => self.default_layer = layer,
+            Action::OneShotLayer(layer) => self.one_shot_layer = Some(layer),
+            Action::CapsWord => self.caps_word ^= true,
+            Action::ToggleAutoShift => {
+                self.auto_shift_enabled ^= true;
+                self.auto_shift_pending = None;
             }
-            (Layer::Raise, SwitchIdentifier { row, col }) => {
-                Self::KEY_CODES_RAISE[row as usize][col as usize]
+            _ => {}
+        }
+    }
+
+    fn process(&mut self, events: &[Event], now: Instant) {
+        let mut queue = Vec::<Event, 32>::new();
+        for event in events {
+            queue.push(*event).ok();
+        }
+        let mut i = 0;
+        while i < queue.len() {
+            let event = queue[i];
+            i += 1;
+            if let Some(replay) = self.handle(event, now) {
+                // 溜めていたイベントを残りのイベントより先に処理する
+                let mut next = Vec::new();
+                for event in replay.iter().chain(&queue[i..]) {
+                    next.push(*event).ok();
+                }
+                queue = next;
+                i = 0;
+            }
+        }
+    }
+
+    /// タップかホールドかが決まったら、溜めていたイベントを返す
+    fn handle(&mut self, event: Event, now: Instant) -> Option<Vec<Event, 16>> {
+        if let Some(pending) = self.pending.as_mut() {
+            if event == Event::Released(pending.trigger) {
+                return Some(self.resolve_tap_hold(false, now));
+            }
+            return pending
+                .push(event, self.tap_hold.mode)
+                .then(|| self.resolve_tap_hold(true, now));
+        }
+
+        if let Some(dance) = self.tap_dance.as_mut() {
+            match event {
+                Event::Pressed(trigger) if trigger == dance.trigger => {
+                    dance.count += 1;
+                    dance.pressed = true;
+                    dance.changed_at = now;
+                    return None;
+                }
+                Event::Released(trigger) if trigger == dance.trigger => {
+                    dance.pressed = false;
+                    dance.changed_at = now;
+                    // 一番多い回数まで来たら待たずに決める
+                    if dance.count as usize >= Self::tap_dance_actions(dance.index).len() {
+                        self.resolve_tap_dance(false, now);
+                    }
+                    return None;
+                }
+                // 他のキーを押したらそこで決める(押したままでもホールドにはしない)
+                Event::Pressed(_) => self.resolve_tap_dance(true, now),
+                Event::Released(_) => {}
+            }
+        }
+
+        if let Some(pending) = self.auto_shift_pending {
+            match event {
+                // Shiftせずに離した
+                Event::Released(Trigger::Switch(switch)) if switch == pending.switch => {
+                    self.auto_shift_pending = None;
+                    self.tap(Action::Key(pending.key), now);
+                    return None;
+                }
+                // 他のキーを押したらShiftしないことにする
+                Event::Pressed(_) => {
+                    self.auto_shift_pending = None;
+                    self.push_active(
+                        Trigger::Switch(pending.switch),
+                        Action::Key(pending.key),
+                        pending.pressed_at,
+                    );
+                }
+                Event::Released(_) => {}
+            }
+        }
+
+        // リーダーキーのあとのキーは送らずにシーケンスと照らし合わせる
+        if let (Some(_), Event::Pressed(Trigger::Switch(switch))) = (&self.leader, event) {
+            if let Action::Key(key) = self.action(self.layer(), &switch) {
+                let matched = self
+                    .leader
+                    .as_mut()
+                    .map(|leader| leader.push(key, &Self::LEADER_SEQUENCES, now));
+                match matched {
+                    Some(Match::Partial) => {}
+                    Some(Match::Complete(action)) => {
+                        self.leader = None;
+                        self.apply_layout_action(action);
+                        self.tap(action, now);
+                    }
+                    Some(Match::None) | None => self.leader = None,
+                }
+                return None;
             }
         }
+
+        match event {
+            Event::Pressed(trigger) => {
+                let action = match trigger {
+                    Trigger::Switch(switch) => self.action(self.layer(), &switch),
+                    Trigger::Combo(index) => Self::COMBOS[index as usize].action,
+                };
+                // ワンショットのレイヤーは次に押したキーのアクションを決めたら消える
+                if !matches!(action, Action::OneShotLayer(_)) {
+                    self.one_shot_layer = None;
+                }
+                match action {
+                    Action::Leader => self.leader = Some(PendingLeader::new(now)),
+                    Action::TapDance(index) => {
+                        self.tap_dance = Some(PendingTapDance {
+                            trigger,
+                            index,
+                            count: 1,
+                            pressed: true,
+                            changed_at: now,
+                        });
+                    }
+                    Action::TapHold(tap_hold) => {
+                        self.pending = Some(PendingTapHold {
+                            trigger,
+                            tap_hold,
+                            pressed_at: now,
+                            events: Vec::new(),
+                        });
+                    }
+                    Action::Key(key) => {
+                        let action = self.caps_word(key);
+                        match (trigger, action) {
+                            (Trigger::Switch(switch), Action::Key(key))
+                                if self.auto_shift_enabled && auto_shift::is_shiftable(key) =>
+                            {
+                                self.auto_shift_pending = Some(PendingAutoShift {
+                                    switch,
+                                    key,
+                                    pressed_at: now,
+                                });
+                            }
+                            _ => self.push_active(trigger, action, now),
+                        }
+                    }
+                    action => {
+                        self.apply_layout_action(action);
+                        self.push_active(trigger, action, now);
+                    }
+                }
+            }
+            Event::Released(trigger) => {
+                let index = self.active.iter().position(|a| a.trigger == trigger)?;
+                let active = self.active.remove(index);
+                // 溜めていたイベントで同時に押して離したキーもタップとして送る
+                if let (Action::Key(key), true) = (active.action, active.pressed_at == now) {
+                    self.tap(Action::Key(key), now);
+                }
+            }
+        }
+        None
+    }
+
+    fn resolve_tap_hold(&mut self, hold: bool, now: Instant) -> Vec<Event, 16> {
+        let Some(pending) = self.pending.take() else {
+            return Vec::new();
+        };
+        if hold {
+            self.active
+                .push(ActiveAction {
+                    trigger: pending.trigger,
+                    action: pending.tap_hold.hold.into(),
+                    pressed_at: pending.pressed_at,
+                    deep: false,
+                })
+                .ok();
+        } else {
+            let action = self.caps_word(pending.tap_hold.tap);
+            self.tap(action, now);
+        }
+        pending.events
+    }
+
+    /// `interrupted`なら押したままのキーはホールドではなくタップのキーにする
+    fn resolve_tap_dance(&mut self, interrupted: bool, now: Instant) {
+        let Some(dance) = self.tap_dance.take() else {
+            return;
+        };
+        let actions = Self::tap_dance_actions(dance.index);
+        let Some(action) = actions.get(dance.count as usize - 1).or(actions.last()) else {
+            return;
+        };
+        if !dance.pressed {
+            let action = self.caps_word(action.tap);
+            self.tap(action, now);
+            return;
+        }
+        let action = match action.hold {
+            Some(hold) if !interrupted => hold.into(),
+            _ => Action::Key(action.tap),
+        };
+        self.active
+            .push(ActiveAction {
+                trigger: dance.trigger,
+                action,
+                pressed_at: dance.changed_at,
+                deep: false,
+            })
+            .ok();
+    }
+
+    // キーマップで編集した知らない添字なら何もしない
+    fn tap_dance_actions(index: u8) -> &'static [TapDanceAction] {
+        Self::TAP_DANCES
+            .get(index as usize)
+            .map_or(&[], |dance| dance.actions)
+    }
+
+    fn push_active(&mut self, trigger: Trigger, action: Action, pressed_at: Instant) {
+        self.active
+            .push(ActiveAction {
+                trigger,
+                action,
+                pressed_at,
+                deep: false,
+            })
+            .ok();
+    }
+
+    // Caps Wordの間は文字をShiftし、単語が終わるキーで抜ける
+    fn caps_word(&mut self, key: Key) -> Action {
+        if !self.caps_word {
+            return Action::Key(key);
+        }
+        caps_word_action(key).unwrap_or_else(|| {
+            self.caps_word = false;
+            Action::Key(key)
+        })
+    }
+
+    fn tap(&mut self, action: Action, now: Instant) {
+        self.taps.push((action, now + Self::TAP_DURATION)).ok();
+    }
+
+    /// `Trn`はデフォルトのレイヤー(それも`Trn`ならDefaultレイヤー)のアクションになる
+    pub fn action(&self, layer: Layer, switch: &SwitchIdentifier) -> Action {
+        [layer, self.default_layer, Layer::Default]
+            .into_iter()
+            .map(|layer| {
+                let action = self.keymap.get(layer, switch);
+                let base = match (layer, self.base_layout) {
+                    (Layer::Default, BaseLayout::Colemak) => &Self::ACTIONS_COLEMAK,
+                    (Layer::Default, BaseLayout::Dvorak) => &Self::ACTIONS_DVORAK,
+                    _ => return action,
+                };
+                // キーマップで編集したキーは配列を変えてもそのまま
+                if action == Self::DEFAULT_KEYMAP.get(layer, switch) {
+                    base[switch.row as usize][switch.col as usize]
+                } else {
+                    action
+                }
+            })
+            .find(|action| *action != Action::Key(Key::Transparent))
+            .unwrap_or(Action::Key(Key::None))
+    }
+
+    /// 押しているマウスキーと、その押し込み量(コンボは一番深く押したものとする)
+    pub fn mouse_actions(&self, depths: &[[f32; 12]; 4]) -> Vec<(MouseAction, f32), 12> {
+        self.active
+            .iter()
+            .filter_map(|active| {
+                let Action::Mouse(action) = active.action else {
+                    return None;
+                };
+                let depth = match active.trigger {
+                    Trigger::Switch(switch) => depths[switch.row as usize][switch.col as usize],
+                    Trigger::Combo(_) => 1.0,
+                };
+                Some((action, depth))
+            })
+            .collect()
+    }
+
+    pub fn base_layout(&self) -> BaseLayout {
+        self.base_layout
+    }
+
+    /// 押しているキーのアクションは離すまで変わらない
+    pub fn set_base_layout(&mut self, base_layout: BaseLayout) {
+        self.base_layout = base_layout;
+    }
+
+    /// 保存を頼まれたキーマップを一度だけ返す(Flashへの保存用)
+    pub fn take_keymap_to_save(&mut self) -> Option<Keymap> {
+        core::mem::take(&mut self.keymap_save_requested).then_some(self.keymap)
+    }
+
+    /// キーマップの1か所をそのまま返す(`Trn`もそのまま)
+    pub fn keymap_entry(&self, layer: Layer, switch: &SwitchIdentifier) -> Action {
+        self.keymap.get(layer, switch)
+    }
+
+    /// 書き換えはすぐに効くが、Flashには`save_keymap`するまで保存しない
+    pub fn set_keymap_entry(&mut self, layer: Layer, switch: &SwitchIdentifier, action: Action) {
+        self.keymap.set(layer, switch, action);
+    }
+
+    /// 組み込みのキーマップに戻す(保存は`save_keymap`で)
+    pub fn reset_keymap(&mut self) {
+        self.keymap = Self::DEFAULT_KEYMAP;
+    }
+
+    /// 次にメインループが回ったときにFlashへ保存する
+    pub fn save_keymap(&mut self) {
+        self.keymap_save_requested = true;
+    }
+}
+
+// 各機能のテストで使うLayoutと時刻
+#[cfg(test)]
+mod testing {
+    use fugit::MicrosDurationU32;
+    use rp2040_hal::timer::Instant;
+
+    use super::{AutoShift, Keymap, Layer, Layout, TapHoldConfig, TapHoldMode};
+    use crate::switches::SwitchIdentifier;
+
+    pub const LOWER: SwitchIdentifier = SwitchIdentifier { row: 3, col: 7 };
+    pub const RAISE: SwitchIdentifier = SwitchIdentifier { row: 3, col: 8 };
+
+    /// どのキーも押し込んでいない
+    pub const DEPTHS: [[f32; 12]; 4] = [[0.0; 12]; 4];
+
+    /// タッピングタームは200msのPermissiveHold(オートシフトはオフ)
+    pub fn layout(keymap: Keymap) -> Layout {
+        let config = TapHoldConfig {
+            tapping_term: MicrosDurationU32::millis(200),
+            mode: TapHoldMode::PermissiveHold,
+        };
+        Layout::new(keymap, config, AutoShift::Depth(0.5))
+    }
+
+    /// ミリ秒の時刻
+    pub fn at(ms: u64) -> Instant {
+        Instant::from_ticks(ms * 1_000)
+    }
+
+    /// `ms`からAdjustレイヤーで`switch`を押して、230ms後に全部離す
+    pub fn tap_on_adjust(layout: &mut Layout, switch: SwitchIdentifier, ms: u64) {
+        layout.update(&[LOWER], &DEPTHS, at(ms));
+        layout.update(&[LOWER], &DEPTHS, at(ms + 200));
+        layout.update(&[LOWER, RAISE], &DEPTHS, at(ms + 210));
+        assert_eq!(layout.layer(), Layer::Adjust);
+        layout.update(&[LOWER, RAISE, switch], &DEPTHS, at(ms + 220));
+        layout.update(&[], &DEPTHS, at(ms + 230));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{at, layout, tap_on_adjust, DEPTHS, LOWER, RAISE};
+    use super::*;
+
+    #[test]
+    fn lower_and_raise_turn_on_adjust() {
+        let mut layout = layout(Layout::DEFAULT_KEYMAP);
+        // Lowerはタッピングタームを過ぎてからホールドになる
+        layout.update(&[LOWER], &DEPTHS, at(0));
+        layout.update(&[LOWER], &DEPTHS, at(200));
+        assert_eq!(layout.layer(), Layer::Lower);
+        layout.update(&[LOWER, RAISE], &DEPTHS, at(250));
+        assert_eq!(layout.layer(), Layer::Adjust);
+
+        layout.update(&[LOWER], &DEPTHS, at(300));
+        assert_eq!(layout.layer(), Layer::Lower);
+        layout.update(&[LOWER, RAISE], &DEPTHS, at(350));
+        assert_eq!(layout.layer(), Layer::Adjust);
+        layout.update(&[RAISE], &DEPTHS, at(400));
+        assert_eq!(layout.layer(), Layer::Raise);
+        layout.update(&[], &DEPTHS, at(450));
+        assert_eq!(layout.layer(), Layer::Default);
+    }
+
+    #[test]
+    fn one_shot_layer_clears_after_the_next_key() {
+        let mut layout = layout(Layout::DEFAULT_KEYMAP);
+        // Adjust + X
+        tap_on_adjust(&mut layout, SwitchIdentifier { row: 2, col: 2 }, 0);
+        assert_eq!(layout.layer(), Layer::Mouse);
+
+        // Fは左ボタン
+        let f = SwitchIdentifier { row: 1, col: 4 };
+        assert_eq!(
+            &layout.update(&[f], &DEPTHS, at(300))[..],
+            &[Action::Mouse(MouseAction::Button1)]
+        );
+        assert_eq!(layout.layer(), Layer::Default);
+        layout.update(&[], &DEPTHS, at(350));
+        assert_eq!(
+            &layout.update(&[f], &DEPTHS, at(400))[..],
+            &[Action::Key(Key::F)]
+        );
+    }
+
+    #[test]
+    fn layer_lock_survives_releasing_the_layer_key() {
+        let mut layout = layout(Layout::DEFAULT_KEYMAP);
+        // Raise + Q
+        let q = SwitchIdentifier { row: 0, col: 1 };
+        layout.update(&[RAISE], &DEPTHS, at(0));
+        layout.update(&[RAISE, q], &DEPTHS, at(10));
+        layout.update(&[], &DEPTHS, at(20));
+        assert_eq!(layout.layer(), Layer::Raise);
+        layout.update(&[RAISE], &DEPTHS, at(30));
+        layout.update(&[], &DEPTHS, at(40));
+        assert_eq!(layout.layer(), Layer::Raise);
+
+        // もう一度押すと戻る
+        layout.update(&[q], &DEPTHS, at(50));
+        layout.update(&[], &DEPTHS, at(60));
+        assert_eq!(layout.layer(), Layer::Default);
+    }
+
+    #[test]
+    fn default_layer_stays_until_changed_back() {
+        let mut layout = layout(Layout::DEFAULT_KEYMAP);
+        let q = SwitchIdentifier { row: 0, col: 1 };
+        // Adjust + D
+        tap_on_adjust(&mut layout, SwitchIdentifier { row: 1, col: 3 }, 0);
+        assert_eq!(layout.layer(), Layer::Lower);
+        assert_eq!(
+            &layout.update(&[q], &DEPTHS, at(300))[..],
+            &[Action::Key(Key::Digit1)]
+        );
+        layout.update(&[], &DEPTHS, at(310));
+        assert_eq!(layout.layer(), Layer::Lower);
+
+        // RaiseだけでAdjustになるので、Raise + Fで戻す
+        layout.update(&[RAISE], &DEPTHS, at(400));
+        assert_eq!(layout.layer(), Layer::Adjust);
+        layout.update(
+            &[RAISE, SwitchIdentifier { row: 1, col: 4 }],
+            &DEPTHS,
+            at(410),
+        );
+        layout.update(&[], &DEPTHS, at(420));
+        assert_eq!(layout.layer(), Layer::Default);
+    }
+
+    #[test]
+    fn toggled_layer_stays_until_toggled_again() {
+        let mut layout = layout(Layout::DEFAULT_KEYMAP);
+        // Adjust + G
+        tap_on_adjust(&mut layout, SwitchIdentifier { row: 1, col: 5 }, 0);
+        assert_eq!(layout.layer(), Layer::Mouse);
+        // MouseレイヤーのEsc
+        let esc = SwitchIdentifier { row: 0, col: 0 };
+        layout.update(&[esc], &DEPTHS, at(300));
+        layout.update(&[], &DEPTHS, at(310));
+        assert_eq!(layout.layer(), Layer::Default);
     }
 }
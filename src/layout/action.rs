@@ -0,0 +1,68 @@
+use rustkbd::keyboard::Key;
+
+use crate::mouse::MouseAction;
+
+use super::{DualStage, Layer, TapHold};
+
+/// Flashへの保存とホストとのやりとりには`encoding.rs`の符号を使う(足したらそちらにも足す)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Key(Key),
+    /// Shiftと一緒に送る
+    Shifted(Key),
+    /// 押している間だけレイヤーを切り替える
+    Layer(Layer),
+    /// 押すたびにレイヤーをオン・オフする
+    ToggleLayer(Layer),
+    /// いまのレイヤーに固定する(もう一度押すと戻る)
+    LayerLock,
+    /// 何も押していないときのレイヤーを変える
+    DefaultLayer(Layer),
+    /// 次に押したキー1つだけレイヤーを切り替える(押している間もそのレイヤー)
+    OneShotLayer(Layer),
+    TapHold(TapHold),
+    /// 押し込む深さで送るキーを変える
+    DualStage(DualStage),
+    /// `Layout::TAP_DANCES`の添字
+    TapDance(u8),
+    /// 押し込む深さで速さが変わる
+    Mouse(MouseAction),
+    /// `Layout::MACROS`の添字
+    Macro(u8),
+    /// 設定で選んだ方法でUnicodeの文字を打つ
+    Unicode(char),
+    /// Unicodeの文字を打つ方法を切り替える
+    CycleUnicodeInput,
+    /// 続けて押したキーを`Layout::LEADER_SEQUENCES`と照らし合わせる
+    Leader,
+    /// 単語を打ち終わるまで文字をShiftする
+    CapsWord,
+    /// Defaultレイヤーの配列(QWERTY、Colemak、Dvorak)を切り替える
+    CycleBaseLayout,
+    /// 長く(深く)押したキーをShift付きで送るモードの切り替え
+    ToggleAutoShift,
+    /// ゲームパッドモードの切り替え(列挙し直すので再起動する)
+    ToggleGamepad,
+    /// OLEDの表示をキーの診断結果に切り替える
+    ToggleDiagnostics,
+    /// USBマスストレージのブートローダーで再起動する
+    Bootloader,
+}
+
+impl Action {
+    pub const fn from_keys<const ROWS: usize, const COLS: usize>(
+        keys: [[Key; COLS]; ROWS],
+    ) -> [[Action; COLS]; ROWS] {
+        let mut actions = [[Action::Key(Key::None); COLS]; ROWS];
+        let mut row = 0;
+        while row < ROWS {
+            let mut col = 0;
+            while col < COLS {
+                actions[row][col] = Action::Key(keys[row][col]);
+                col += 1;
+            }
+            row += 1;
+        }
+        actions
+    }
+}
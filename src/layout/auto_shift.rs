@@ -0,0 +1,145 @@
+use fugit::MicrosDurationU32;
+use rp2040_hal::timer::Instant;
+use rustkbd::keyboard::Key;
+
+use crate::{
+    macros::{DIGITS, LETTERS},
+    switches::SwitchIdentifier,
+};
+
+/// どうなったらShift付きで送るか
+#[derive(Debug, Clone, Copy)]
+pub enum AutoShift {
+    /// この時間より長く押したら
+    Hold(MicrosDurationU32),
+    /// この深さ(0.0〜1.0)より深く押したら
+    Depth(f32),
+}
+
+/// Shiftするかまだ決まっていないキー
+#[derive(Debug, Clone, Copy)]
+pub(super) struct PendingAutoShift {
+    pub switch: SwitchIdentifier,
+    pub key: Key,
+    pub pressed_at: Instant,
+}
+
+impl PendingAutoShift {
+    pub fn should_shift(
+        &self,
+        auto_shift: AutoShift,
+        depths: &[[f32; 12]; 4],
+        now: Instant,
+    ) -> bool {
+        match auto_shift {
+            AutoShift::Hold(duration) => now - self.pressed_at >= duration,
+            AutoShift::Depth(depth) => {
+                depths[self.switch.row as usize][self.switch.col as usize] >= depth
+            }
+        }
+    }
+}
+
+/// Shiftすると別の文字になるキー
+pub(super) fn is_shiftable(key: Key) -> bool {
+    LETTERS.contains(&key)
+        || DIGITS.contains(&key)
+        || matches!(
+            key,
+            Key::Minus
+                | Key::Equal
+                | Key::LeftBracket
+                | Key::RightBracket
+                | Key::Backslash
+                | Key::Semicolon
+                | Key::Quote
+                | Key::Grave
+                | Key::Comma
+                | Key::Period
+                | Key::Slash
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::{
+        testing::{at, layout, tap_on_adjust, DEPTHS},
+        Action, Layout,
+    };
+
+    const A: SwitchIdentifier = SwitchIdentifier { row: 1, col: 1 };
+    const D: SwitchIdentifier = SwitchIdentifier { row: 1, col: 3 };
+    const ENTER: SwitchIdentifier = SwitchIdentifier { row: 2, col: 11 };
+
+    // Adjust + Sでオートシフトにする
+    fn auto_shift_layout(auto_shift: AutoShift) -> Layout {
+        let mut layout = layout(Layout::DEFAULT_KEYMAP);
+        layout.auto_shift = auto_shift;
+        tap_on_adjust(&mut layout, SwitchIdentifier { row: 1, col: 2 }, 0);
+        layout
+    }
+
+    fn depths(switch: SwitchIdentifier, depth: f32) -> [[f32; 12]; 4] {
+        let mut depths = DEPTHS;
+        depths[switch.row as usize][switch.col as usize] = depth;
+        depths
+    }
+
+    #[test]
+    fn shifts_keys_pressed_deep() {
+        let mut layout = auto_shift_layout(AutoShift::Depth(0.9));
+        assert!(layout.update(&[A], &depths(A, 0.6), at(300)).is_empty());
+        assert_eq!(
+            &layout.update(&[A], &depths(A, 0.95), at(310))[..],
+            &[Action::Shifted(Key::A)]
+        );
+        // 浅く戻してもShiftしたまま
+        assert_eq!(
+            &layout.update(&[A], &depths(A, 0.6), at(320))[..],
+            &[Action::Shifted(Key::A)]
+        );
+        assert!(layout.update(&[], &DEPTHS, at(330)).is_empty());
+    }
+
+    #[test]
+    fn shifts_keys_held_long() {
+        let mut layout = auto_shift_layout(AutoShift::Hold(MicrosDurationU32::millis(150)));
+        assert!(layout.update(&[A], &DEPTHS, at(300)).is_empty());
+        assert!(layout.update(&[A], &DEPTHS, at(449)).is_empty());
+        assert_eq!(
+            &layout.update(&[A], &DEPTHS, at(450))[..],
+            &[Action::Shifted(Key::A)]
+        );
+    }
+
+    #[test]
+    fn taps_keys_released_early() {
+        let mut layout = auto_shift_layout(AutoShift::Depth(0.9));
+        layout.update(&[A], &depths(A, 0.6), at(300));
+        assert_eq!(
+            &layout.update(&[], &DEPTHS, at(310))[..],
+            &[Action::Key(Key::A)]
+        );
+    }
+
+    #[test]
+    fn sends_the_key_unshifted_when_another_key_is_pressed() {
+        let mut layout = auto_shift_layout(AutoShift::Depth(0.9));
+        layout.update(&[A], &depths(A, 0.6), at(300));
+        // Dも待つのでAだけ
+        assert_eq!(
+            &layout.update(&[A, D], &depths(A, 0.6), at(310))[..],
+            &[Action::Key(Key::A)]
+        );
+    }
+
+    #[test]
+    fn sends_other_keys_at_once() {
+        let mut layout = auto_shift_layout(AutoShift::Depth(0.9));
+        assert_eq!(
+            &layout.update(&[ENTER], &DEPTHS, at(300))[..],
+            &[Action::Key(Key::Enter)]
+        );
+    }
+}
@@ -0,0 +1,90 @@
+/// Defaultレイヤーの文字の配列(LowerやRaiseはどれでも同じ)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BaseLayout {
+    /// キーマップのDefaultレイヤーをそのまま使う
+    #[default]
+    Qwerty,
+    Colemak,
+    Dvorak,
+}
+
+impl BaseLayout {
+    pub fn next(self) -> BaseLayout {
+        match self {
+            BaseLayout::Qwerty => BaseLayout::Colemak,
+            BaseLayout::Colemak => BaseLayout::Dvorak,
+            BaseLayout::Dvorak => BaseLayout::Qwerty,
+        }
+    }
+
+    /// OLEDに出す2文字
+    pub fn label(self) -> &'static str {
+        match self {
+            BaseLayout::Qwerty => "QW",
+            BaseLayout::Colemak => "CM",
+            BaseLayout::Dvorak => "DV",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustkbd::keyboard::Key;
+
+    use super::*;
+    use crate::{
+        layout::{testing, Action, Keymap, Layer, Layout},
+        switches::SwitchIdentifier,
+    };
+
+    fn layout(keymap: Keymap, base_layout: BaseLayout) -> Layout {
+        let mut layout = testing::layout(keymap);
+        layout.set_base_layout(base_layout);
+        layout
+    }
+
+    fn action(layout: &Layout, row: u8, col: u8) -> Action {
+        layout.action(Layer::Default, &SwitchIdentifier { row, col })
+    }
+
+    #[test]
+    fn puts_the_tap_dance_on_quote_in_every_base_layout() {
+        let keymap = Layout::DEFAULT_KEYMAP;
+        assert_eq!(
+            action(&layout(keymap, BaseLayout::Qwerty), 1, 11),
+            Action::TapDance(0)
+        );
+        assert_eq!(
+            action(&layout(keymap, BaseLayout::Colemak), 1, 11),
+            Action::TapDance(0)
+        );
+        let dvorak = layout(keymap, BaseLayout::Dvorak);
+        assert_eq!(action(&dvorak, 0, 1), Action::TapDance(0));
+        assert_eq!(action(&dvorak, 1, 11), Action::Key(Key::Minus));
+    }
+
+    #[test]
+    fn keeps_keys_edited_in_the_keymap() {
+        let mut keymap = Layout::DEFAULT_KEYMAP;
+        // QWERTYのE
+        keymap.set(
+            Layer::Default,
+            &SwitchIdentifier { row: 0, col: 3 },
+            Action::Key(Key::F5),
+        );
+        let dvorak = layout(keymap, BaseLayout::Dvorak);
+        assert_eq!(action(&dvorak, 0, 3), Action::Key(Key::F5));
+        // 編集していないキーはDvorak
+        assert_eq!(action(&dvorak, 0, 4), Action::Key(Key::P));
+    }
+
+    #[test]
+    fn keeps_the_layer_keys() {
+        let colemak = layout(Layout::DEFAULT_KEYMAP, BaseLayout::Colemak);
+        assert_eq!(action(&colemak, 3, 8), Action::Layer(Layer::Raise));
+        assert_eq!(
+            colemak.action(Layer::Lower, &SwitchIdentifier { row: 0, col: 1 }),
+            Action::Key(Key::Digit1)
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use rustkbd::keyboard::Key;
+
+use crate::macros::{DIGITS, LETTERS};
+
+use super::Action;
+
+/// Caps Wordの間に押したキーのアクション。単語が終わるキーならNone
+pub(super) fn caps_word_action(key: Key) -> Option<Action> {
+    match key {
+        _ if LETTERS.contains(&key) => Some(Action::Shifted(key)),
+        Key::Minus => Some(Action::Key(Key::Underscore)),
+        Key::Backspace | Key::Delete | Key::Underscore | Key::LeftShift | Key::RightShift => {
+            Some(Action::Key(key))
+        }
+        _ if DIGITS.contains(&key) => Some(Action::Key(key)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustkbd::Vec;
+
+    use super::*;
+    use crate::{
+        layout::{
+            testing::{at, layout, tap_on_adjust, DEPTHS},
+            Layer, Layout,
+        },
+        switches::SwitchIdentifier,
+    };
+
+    const A: SwitchIdentifier = SwitchIdentifier { row: 1, col: 1 };
+    const COMMA: SwitchIdentifier = SwitchIdentifier { row: 2, col: 8 };
+    // 数字と-はLowerにあるので、QとWに置く
+    const DIGIT: SwitchIdentifier = SwitchIdentifier { row: 0, col: 1 };
+    const MINUS: SwitchIdentifier = SwitchIdentifier { row: 0, col: 2 };
+
+    // Adjust + CでCaps Wordにする
+    fn caps_word_layout() -> Layout {
+        let mut keymap = Layout::DEFAULT_KEYMAP;
+        keymap.set(Layer::Default, &DIGIT, Action::Key(Key::Digit1));
+        keymap.set(Layer::Default, &MINUS, Action::Key(Key::Minus));
+        let mut layout = layout(keymap);
+        tap_on_adjust(&mut layout, SwitchIdentifier { row: 2, col: 3 }, 0);
+        layout
+    }
+
+    fn type_key(layout: &mut Layout, switch: SwitchIdentifier, ms: u64) -> Vec<Action, 16> {
+        let actions = layout.update(&[switch], &DEPTHS, at(ms));
+        layout.update(&[], &DEPTHS, at(ms + 10));
+        actions
+    }
+
+    #[test]
+    fn continues_on_letters_digits_and_minus() {
+        let mut layout = caps_word_layout();
+        assert_eq!(
+            &type_key(&mut layout, A, 300)[..],
+            &[Action::Shifted(Key::A)]
+        );
+        assert_eq!(
+            &type_key(&mut layout, DIGIT, 320)[..],
+            &[Action::Key(Key::Digit1)]
+        );
+        assert_eq!(
+            &type_key(&mut layout, MINUS, 340)[..],
+            &[Action::Key(Key::Underscore)]
+        );
+        assert_eq!(
+            &type_key(&mut layout, A, 360)[..],
+            &[Action::Shifted(Key::A)]
+        );
+    }
+
+    #[test]
+    fn ends_on_other_keys() {
+        let mut layout = caps_word_layout();
+        assert_eq!(
+            &type_key(&mut layout, A, 300)[..],
+            &[Action::Shifted(Key::A)]
+        );
+        assert_eq!(
+            &type_key(&mut layout, COMMA, 320)[..],
+            &[Action::Key(Key::Comma)]
+        );
+        assert_eq!(&type_key(&mut layout, A, 340)[..], &[Action::Key(Key::A)]);
+    }
+}
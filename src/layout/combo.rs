@@ -0,0 +1,213 @@
+use fugit::MicrosDurationU32;
+use rp2040_hal::timer::Instant;
+use rustkbd::Vec;
+
+use crate::switches::SwitchIdentifier;
+
+use super::{Action, Event, Layer, Trigger};
+
+/// 同時に押すと、それぞれのキーの代わりに別のアクションになるスイッチの組
+#[derive(Debug, Clone, Copy)]
+pub struct Combo {
+    pub switches: &'static [SwitchIdentifier],
+    pub action: Action,
+    /// Noneならどのレイヤーでも
+    pub layers: Option<&'static [Layer]>,
+}
+
+impl Combo {
+    fn is_enabled(&self, layer: Layer) -> bool {
+        match self.layers {
+            Some(layers) => layers.contains(&layer),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ActiveCombo {
+    index: u8,
+    // コンボのスイッチのうちまだ押されているもの
+    held: Vec<SwitchIdentifier, 4>,
+    released: bool,
+}
+
+/// コンボの途中かもしれないスイッチのイベントを溜めておき、コンボのイベントに置き換える
+#[derive(Debug, Clone, Default)]
+pub(super) struct ComboState {
+    pending: Vec<SwitchIdentifier, 4>,
+    started_at: Option<Instant>,
+    active: Vec<ActiveCombo, 4>,
+}
+
+impl ComboState {
+    pub fn handle(
+        &mut self,
+        combos: &[Combo],
+        event: Event,
+        layer: Layer,
+        now: Instant,
+    ) -> Vec<Event, 8> {
+        let mut events = Vec::new();
+        match event {
+            Event::Pressed(Trigger::Switch(switch)) => {
+                let mut candidate = self.pending.clone();
+                candidate.push(switch).ok();
+                if !Self::can_complete(combos, &candidate, layer) {
+                    self.flush(&mut events);
+                    candidate.clear();
+                    candidate.push(switch).ok();
+                    if !Self::can_complete(combos, &candidate, layer) {
+                        events.push(event).ok();
+                        return events;
+                    }
+                }
+
+                self.pending = candidate;
+                self.started_at.get_or_insert(now);
+                let completed = combos.iter().position(|combo| {
+                    combo.is_enabled(layer)
+                        && combo.switches.len() == self.pending.len()
+                        && self.pending.iter().all(|s| combo.switches.contains(s))
+                });
+                if let Some(index) = completed {
+                    events
+                        .push(Event::Pressed(Trigger::Combo(index as u8)))
+                        .ok();
+                    self.active
+                        .push(ActiveCombo {
+                            index: index as u8,
+                            held: core::mem::take(&mut self.pending),
+                            released: false,
+                        })
+                        .ok();
+                    self.started_at = None;
+                }
+            }
+            Event::Released(Trigger::Switch(switch)) => {
+                if self.pending.contains(&switch) {
+                    self.flush(&mut events);
+                    events.push(event).ok();
+                } else if let Some(combo) =
+                    self.active.iter_mut().find(|c| c.held.contains(&switch))
+                {
+                    // どれか1つを離したらコンボも離したことにする
+                    combo.held.retain(|s| *s != switch);
+                    if !core::mem::replace(&mut combo.released, true) {
+                        events
+                            .push(Event::Released(Trigger::Combo(combo.index)))
+                            .ok();
+                    }
+                    self.active.retain(|c| !c.held.is_empty());
+                } else {
+                    events.push(event).ok();
+                }
+            }
+            _ => {
+                events.push(event).ok();
+            }
+        }
+        events
+    }
+
+    /// 時間内に揃わなければ溜めていたイベントを返す
+    pub fn expire(&mut self, now: Instant, term: MicrosDurationU32) -> Vec<Event, 8> {
+        let mut events = Vec::new();
+        if self
+            .started_at
+            .is_some_and(|started_at| now - started_at >= term)
+        {
+            self.flush(&mut events);
+        }
+        events
+    }
+
+    fn flush(&mut self, events: &mut Vec<Event, 8>) {
+        for switch in self.pending.iter() {
+            events.push(Event::Pressed(Trigger::Switch(*switch))).ok();
+        }
+        self.pending.clear();
+        self.started_at = None;
+    }
+
+    // このスイッチの組にまだ押し足せば揃うコンボがあるか
+    fn can_complete(combos: &[Combo], switches: &[SwitchIdentifier], layer: Layer) -> bool {
+        combos.iter().any(|combo| {
+            combo.is_enabled(layer)
+                && switches.len() <= combo.switches.len()
+                && switches.iter().all(|s| combo.switches.contains(s))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustkbd::keyboard::Key;
+
+    use super::*;
+    use crate::layout::testing::at;
+
+    const J: SwitchIdentifier = SwitchIdentifier { row: 1, col: 7 };
+    const K: SwitchIdentifier = SwitchIdentifier { row: 1, col: 8 };
+    const L: SwitchIdentifier = SwitchIdentifier { row: 1, col: 9 };
+    const COMBOS: [Combo; 1] = [Combo {
+        switches: &[J, K],
+        action: Action::Key(Key::Escape),
+        layers: Some(&[Layer::Default]),
+    }];
+    const TERM: MicrosDurationU32 = MicrosDurationU32::millis(50);
+
+    fn pressed(switch: SwitchIdentifier) -> Event {
+        Event::Pressed(Trigger::Switch(switch))
+    }
+
+    fn released(switch: SwitchIdentifier) -> Event {
+        Event::Released(Trigger::Switch(switch))
+    }
+
+    #[test]
+    fn replaces_switches_pressed_together() {
+        let mut state = ComboState::default();
+        let mut handle = |event, ms| state.handle(&COMBOS, event, Layer::Default, at(ms));
+        assert!(handle(pressed(J), 0).is_empty());
+        assert_eq!(
+            &handle(pressed(K), 10)[..],
+            &[Event::Pressed(Trigger::Combo(0))]
+        );
+        assert_eq!(
+            &handle(released(K), 100)[..],
+            &[Event::Released(Trigger::Combo(0))]
+        );
+        assert!(handle(released(J), 110).is_empty());
+    }
+
+    #[test]
+    fn passes_the_switch_on_after_the_term() {
+        let mut state = ComboState::default();
+        assert!(state
+            .handle(&COMBOS, pressed(J), Layer::Default, at(0))
+            .is_empty());
+        assert!(state.expire(at(49), TERM).is_empty());
+        assert_eq!(&state.expire(at(50), TERM)[..], &[pressed(J)]);
+    }
+
+    #[test]
+    fn passes_switches_on_when_the_combo_cannot_complete() {
+        let mut state = ComboState::default();
+        let mut handle = |event, ms| state.handle(&COMBOS, event, Layer::Default, at(ms));
+        assert!(handle(pressed(J), 0).is_empty());
+        assert_eq!(&handle(pressed(L), 10)[..], &[pressed(J), pressed(L)]);
+
+        assert!(handle(pressed(K), 20).is_empty());
+        assert_eq!(&handle(released(K), 30)[..], &[pressed(K), released(K)]);
+    }
+
+    #[test]
+    fn ignores_combos_on_other_layers() {
+        let mut state = ComboState::default();
+        assert_eq!(
+            &state.handle(&COMBOS, pressed(J), Layer::Lower, at(0))[..],
+            &[pressed(J)]
+        );
+    }
+}
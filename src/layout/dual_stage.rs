@@ -0,0 +1,100 @@
+use rustkbd::keyboard::Key;
+
+/// 浅く押したときと深く押したときで別のキーを送る
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DualStage {
+    /// 普通に押したときのキー
+    pub shallow: Key,
+    /// `depth`より深く押したときのキー
+    pub deep: Key,
+    /// キャリブレーションの範囲を100とした深さ
+    pub depth: u8,
+    /// 深く押している間も`shallow`を送り続ける(`deep`を足す)
+    pub keep_shallow: bool,
+}
+
+impl DualStage {
+    // 深い段から戻るときはこれだけ浅くなるまで待つ
+    const HYSTERESIS: f32 = 0.05;
+
+    /// `deep`はいまの段(スキャンごとに更新する)
+    pub(super) fn is_deep(&self, depth: f32, deep: bool) -> bool {
+        let threshold = self.depth as f32 / 100.0;
+        if deep {
+            depth >= threshold - Self::HYSTERESIS
+        } else {
+            depth >= threshold
+        }
+    }
+
+    pub(super) fn keys(&self, deep: bool) -> impl Iterator<Item = Key> {
+        let shallow = (!deep || self.keep_shallow).then_some(self.shallow);
+        let deep = deep.then_some(self.deep);
+        shallow.into_iter().chain(deep)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        layout::{
+            testing::{at, layout, DEPTHS},
+            Action, Layer, Layout,
+        },
+        switches::SwitchIdentifier,
+    };
+
+    const WALK: DualStage = DualStage {
+        shallow: Key::W,
+        deep: Key::LeftShift,
+        depth: 80,
+        keep_shallow: true,
+    };
+
+    fn keys(dual_stage: &DualStage, deep: bool) -> std::vec::Vec<Key> {
+        dual_stage.keys(deep).collect()
+    }
+
+    #[test]
+    fn goes_deep_past_the_depth_with_hysteresis() {
+        assert!(!WALK.is_deep(0.79, false));
+        assert!(WALK.is_deep(0.8, false));
+        // 戻るときは0.75まで深いまま
+        assert!(WALK.is_deep(0.76, true));
+        assert!(!WALK.is_deep(0.74, true));
+    }
+
+    #[test]
+    fn sends_keys_by_stage() {
+        assert_eq!(keys(&WALK, false), [Key::W]);
+        assert_eq!(keys(&WALK, true), [Key::W, Key::LeftShift]);
+
+        let replace = DualStage {
+            keep_shallow: false,
+            ..WALK
+        };
+        assert_eq!(keys(&replace, false), [Key::W]);
+        assert_eq!(keys(&replace, true), [Key::LeftShift]);
+    }
+
+    #[test]
+    fn layout_follows_the_depth_of_raise_w() {
+        let mut layout = layout(Layout::DEFAULT_KEYMAP);
+        let raise = SwitchIdentifier { row: 3, col: 8 };
+        let w = SwitchIdentifier { row: 0, col: 2 };
+        let mut update = |depth: f32, ms: u64| {
+            let mut depths = DEPTHS;
+            depths[0][2] = depth;
+            layout.update(&[raise, w], &depths, at(ms))
+        };
+
+        let raised = Action::Layer(Layer::Raise);
+        assert_eq!(&update(0.5, 0)[..], &[raised, Action::Key(Key::W)]);
+        assert_eq!(
+            &update(0.9, 10)[..],
+            &[raised, Action::Key(Key::W), Action::Key(Key::LeftShift)]
+        );
+        assert_eq!(&update(0.5, 20)[..], &[raised, Action::Key(Key::W)]);
+    }
+}
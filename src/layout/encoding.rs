@@ -0,0 +1,214 @@
+use rustkbd::keyboard::Key;
+
+use crate::mouse::MouseAction;
+
+use super::{Action, DualStage, Hold, Layer, TapHold};
+
+/// アクション1つを符号にしたときのバイト数(種類と引数4バイト)
+pub const ACTION_SIZE: usize = 5;
+
+// 保存したキーマップが読めなくならないよう、どの表も並びは変えずに末尾に足す
+#[rustfmt::skip]
+const KEYS: [Key; 106] = [
+    Key::None, Key::Transparent, Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H,
+    Key::I, Key::J, Key::K, Key::L, Key::M, Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T,
+    Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z, Key::Digit1, Key::Digit2, Key::Digit3,
+    Key::Digit4, Key::Digit5, Key::Digit6, Key::Digit7, Key::Digit8, Key::Digit9, Key::Digit0,
+    Key::Escape, Key::Tab, Key::Enter, Key::Space, Key::Backspace, Key::Delete, Key::Minus,
+    Key::Equal, Key::LeftBracket, Key::RightBracket, Key::Backslash, Key::Semicolon, Key::Quote,
+    Key::Grave, Key::Comma, Key::Period, Key::Slash, Key::Exclamation, Key::At, Key::Hash,
+    Key::Dollar, Key::Percent, Key::Caret, Key::Ampersand, Key::Asterisk, Key::LeftParenthesis,
+    Key::RightParenthesis, Key::Underscore, Key::Plus, Key::LeftCurlyBrace, Key::RightCurlyBrace,
+    Key::Pipe, Key::Colon, Key::DoubleQuote, Key::Tilde, Key::LessThan, Key::GreaterThan,
+    Key::Question, Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7, Key::F8, Key::F9,
+    Key::F10, Key::F11, Key::F12, Key::Up, Key::Down, Key::Left, Key::Right, Key::LeftControl,
+    Key::LeftShift, Key::LeftAlt, Key::LeftGui, Key::RightControl, Key::RightShift, Key::RightAlt,
+    Key::RightGui, Key::MediaVolumeDown, Key::MediaMute, Key::MediaVolumeUp, Key::MediaPrevTrack,
+    Key::MediaPlayPause, Key::MediaNextTrack,
+];
+
+const MOUSE_ACTIONS: [MouseAction; 11] = [
+    MouseAction::Up,
+    MouseAction::Down,
+    MouseAction::Left,
+    MouseAction::Right,
+    MouseAction::WheelUp,
+    MouseAction::WheelDown,
+    MouseAction::WheelLeft,
+    MouseAction::WheelRight,
+    MouseAction::Button1,
+    MouseAction::Button2,
+    MouseAction::Button3,
+];
+
+fn index_of<T: PartialEq>(table: &[T], value: &T) -> Option<u8> {
+    table.iter().position(|v| v == value).map(|i| i as u8)
+}
+
+fn key(code: u8) -> Option<Key> {
+    KEYS.get(code as usize).copied()
+}
+
+fn layer(code: u8) -> Option<Layer> {
+    Layer::ALL.get(code as usize).copied()
+}
+
+impl Action {
+    /// Flashへの保存とホストとのやりとりに使う符号(表にないキーはNone)
+    pub fn to_bytes(&self) -> Option<[u8; ACTION_SIZE]> {
+        let key = |key: &Key| index_of(&KEYS, key);
+        let layer = |layer: &Layer| index_of(&Layer::ALL, layer);
+        let bytes = match self {
+            Action::Key(k) => [0, key(k)?, 0, 0, 0],
+            Action::Shifted(k) => [1, key(k)?, 0, 0, 0],
+            Action::Layer(l) => [2, layer(l)?, 0, 0, 0],
+            Action::ToggleLayer(l) => [3, layer(l)?, 0, 0, 0],
+            Action::LayerLock => [4, 0, 0, 0, 0],
+            Action::DefaultLayer(l) => [5, layer(l)?, 0, 0, 0],
+            Action::OneShotLayer(l) => [6, layer(l)?, 0, 0, 0],
+            Action::TapHold(TapHold { tap, hold }) => match hold {
+                Hold::Key(hold) => [7, key(tap)?, 0, key(hold)?, 0],
+                Hold::Layer(hold) => [7, key(tap)?, 1, layer(hold)?, 0],
+            },
+            Action::DualStage(DualStage {
+                shallow,
+                deep,
+                depth,
+                keep_shallow,
+            }) => [8, key(shallow)?, key(deep)?, *depth, *keep_shallow as u8],
+            Action::TapDance(index) => [9, *index, 0, 0, 0],
+            Action::Mouse(action) => [10, index_of(&MOUSE_ACTIONS, action)?, 0, 0, 0],
+            Action::Macro(index) => [11, *index, 0, 0, 0],
+            Action::Unicode(ch) => {
+                let [a, b, c, _] = (*ch as u32).to_le_bytes();
+                [12, a, b, c, 0]
+            }
+            Action::CycleUnicodeInput => [13, 0, 0, 0, 0],
+            Action::Leader => [14, 0, 0, 0, 0],
+            Action::CapsWord => [15, 0, 0, 0, 0],
+            Action::CycleBaseLayout => [16, 0, 0, 0, 0],
+            Action::ToggleAutoShift => [17, 0, 0, 0, 0],
+            Action::ToggleGamepad => [18, 0, 0, 0, 0],
+            Action::ToggleDiagnostics => [19, 0, 0, 0, 0],
+            Action::Bootloader => [20, 0, 0, 0, 0],
+        };
+        Some(bytes)
+    }
+
+    /// 知らない種類や範囲外の値ならNone
+    pub fn from_bytes(bytes: [u8; ACTION_SIZE]) -> Option<Action> {
+        let [tag, a, b, c, d] = bytes;
+        let action = match tag {
+            0 => Action::Key(key(a)?),
+            1 => Action::Shifted(key(a)?),
+            2 => Action::Layer(layer(a)?),
+            3 => Action::ToggleLayer(layer(a)?),
+            4 => Action::LayerLock,
+            5 => Action::DefaultLayer(layer(a)?),
+            6 => Action::OneShotLayer(layer(a)?),
+            7 => Action::TapHold(TapHold {
+                tap: key(a)?,
+                hold: match b {
+                    0 => Hold::Key(key(c)?),
+                    1 => Hold::Layer(layer(c)?),
+                    _ => return None,
+                },
+            }),
+            8 => Action::DualStage(DualStage {
+                shallow: key(a)?,
+                deep: key(b)?,
+                depth: c,
+                keep_shallow: match d {
+                    0 => false,
+                    1 => true,
+                    _ => return None,
+                },
+            }),
+            9 => Action::TapDance(a),
+            10 => Action::Mouse(*MOUSE_ACTIONS.get(a as usize)?),
+            11 => Action::Macro(a),
+            12 => Action::Unicode(char::from_u32(u32::from_le_bytes([a, b, c, 0]))?),
+            13 => Action::CycleUnicodeInput,
+            14 => Action::Leader,
+            15 => Action::CapsWord,
+            16 => Action::CycleBaseLayout,
+            17 => Action::ToggleAutoShift,
+            18 => Action::ToggleGamepad,
+            19 => Action::ToggleDiagnostics,
+            20 => Action::Bootloader,
+            _ => return None,
+        };
+        Some(action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_every_kind_of_action() {
+        let actions = [
+            Action::Key(Key::Transparent),
+            Action::Shifted(Key::MediaNextTrack),
+            Action::Layer(Layer::Mouse),
+            Action::ToggleLayer(Layer::Lower),
+            Action::LayerLock,
+            Action::DefaultLayer(Layer::Raise),
+            Action::OneShotLayer(Layer::Adjust),
+            Action::TapHold(TapHold {
+                tap: Key::Space,
+                hold: Hold::Layer(Layer::Lower),
+            }),
+            Action::TapHold(TapHold {
+                tap: Key::Z,
+                hold: Hold::Key(Key::LeftShift),
+            }),
+            Action::DualStage(DualStage {
+                shallow: Key::W,
+                deep: Key::LeftShift,
+                depth: 80,
+                keep_shallow: true,
+            }),
+            Action::TapDance(3),
+            Action::Mouse(MouseAction::Button3),
+            Action::Macro(1),
+            Action::Unicode('→'),
+            Action::Unicode('\u{1f431}'),
+            Action::CycleUnicodeInput,
+            Action::Leader,
+            Action::CapsWord,
+            Action::CycleBaseLayout,
+            Action::ToggleAutoShift,
+            Action::ToggleGamepad,
+            Action::ToggleDiagnostics,
+            Action::Bootloader,
+        ];
+        for action in actions {
+            let bytes = action.to_bytes().unwrap();
+            assert_eq!(Action::from_bytes(bytes), Some(action));
+        }
+    }
+
+    #[test]
+    fn codes_stay_the_same() {
+        assert_eq!(Action::Key(Key::None).to_bytes(), Some([0, 0, 0, 0, 0]));
+        assert_eq!(Action::Key(Key::A).to_bytes(), Some([0, 2, 0, 0, 0]));
+        assert_eq!(
+            Action::Layer(Layer::Raise).to_bytes(),
+            Some([2, 2, 0, 0, 0])
+        );
+        assert_eq!(Action::Bootloader.to_bytes(), Some([20, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn rejects_unknown_bytes() {
+        assert_eq!(Action::from_bytes([0xfe, 0, 0, 0, 0]), None);
+        assert_eq!(Action::from_bytes([0, KEYS.len() as u8, 0, 0, 0]), None);
+        assert_eq!(Action::from_bytes([2, Layer::COUNT as u8, 0, 0, 0]), None);
+        assert_eq!(Action::from_bytes([7, 2, 2, 0, 0]), None);
+        assert_eq!(Action::from_bytes([8, 2, 2, 50, 2]), None);
+        // サロゲート
+        assert_eq!(Action::from_bytes([12, 0x00, 0xd8, 0, 0]), None);
+    }
+}
@@ -0,0 +1,20 @@
+//! keymap.txtのキーコードの表
+
+use rustkbd::keyboard::Key;
+
+include!(concat!(env!("OUT_DIR"), "/key_codes.rs"));
+
+#[cfg(test)]
+mod tests {
+    // build.rsがkeymap.txtから作ったREADMEの表
+    const README_TABLE: &str = include_str!(concat!(env!("OUT_DIR"), "/readme_keymap.md"));
+
+    #[test]
+    fn readme_shows_the_keymap() {
+        let readme = include_str!("../../README.md");
+        assert!(
+            readme.contains(README_TABLE),
+            "README.md is out of date with keymap.txt. Replace its keymap with:\n{README_TABLE}"
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use rustkbd::{keyboard::Key, Vec};
+
+use super::Action;
+
+/// 修飾キーと一緒に押したキーを別のキーに置き換える
+///
+/// 置き換えている間は`modifier`をレポートから外す。`replacement`がShift付きの記号なら
+/// Shiftはレポートを作るときに足される
+#[derive(Debug, Clone, Copy)]
+pub struct KeyOverride {
+    /// 左右どちらでもよい
+    pub modifier: Key,
+    pub key: Key,
+    pub replacement: Key,
+}
+
+impl KeyOverride {
+    fn is_held(&self, actions: &[Action]) -> bool {
+        actions.iter().any(
+            |action| matches!(*action, Action::Key(key) if is_same_modifier(key, self.modifier)),
+        )
+    }
+}
+
+/// 解決したアクションに置き換えを当てる(Shiftedなど、修飾キーがアクションに含まれているものは置き換えない)
+pub(super) fn apply<const N: usize>(overrides: &[KeyOverride], actions: &mut Vec<Action, N>) {
+    let mut suppressed = Vec::<Key, 4>::new();
+    for key_override in overrides {
+        if !key_override.is_held(actions) {
+            continue;
+        }
+        let mut replaced = false;
+        for action in actions.iter_mut() {
+            if *action == Action::Key(key_override.key) {
+                *action = Action::Key(key_override.replacement);
+                replaced = true;
+            }
+        }
+        if replaced && !suppressed.contains(&key_override.modifier) {
+            suppressed.push(key_override.modifier).ok();
+        }
+    }
+    actions.retain(|action| match *action {
+        Action::Key(key) => !suppressed
+            .iter()
+            .any(|modifier| is_same_modifier(key, *modifier)),
+        _ => true,
+    });
+}
+
+fn is_same_modifier(key: Key, modifier: Key) -> bool {
+    key == modifier
+        || matches!(
+            (key, modifier),
+            (Key::LeftShift, Key::RightShift)
+                | (Key::RightShift, Key::LeftShift)
+                | (Key::LeftControl, Key::RightControl)
+                | (Key::RightControl, Key::LeftControl)
+                | (Key::LeftAlt, Key::RightAlt)
+                | (Key::RightAlt, Key::LeftAlt)
+                | (Key::LeftGui, Key::RightGui)
+                | (Key::RightGui, Key::LeftGui)
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OVERRIDES: [KeyOverride; 1] = [KeyOverride {
+        modifier: Key::LeftShift,
+        key: Key::Delete,
+        replacement: Key::Backspace,
+    }];
+
+    fn applied(actions: &[Action]) -> Vec<Action, 8> {
+        let mut actions = Vec::from_slice(actions).unwrap();
+        apply(&OVERRIDES, &mut actions);
+        actions
+    }
+
+    #[test]
+    fn replaces_the_key_and_drops_the_modifier() {
+        assert_eq!(
+            &applied(&[Action::Key(Key::LeftShift), Action::Key(Key::Delete)])[..],
+            &[Action::Key(Key::Backspace)]
+        );
+        assert_eq!(
+            &applied(&[Action::Key(Key::RightShift), Action::Key(Key::Delete)])[..],
+            &[Action::Key(Key::Backspace)]
+        );
+    }
+
+    #[test]
+    fn drops_the_modifier_only_while_replacing() {
+        let actions = [
+            Action::Key(Key::LeftShift),
+            Action::Key(Key::Delete),
+            Action::Key(Key::A),
+        ];
+        assert_eq!(
+            &applied(&actions)[..],
+            &[Action::Key(Key::Backspace), Action::Key(Key::A)]
+        );
+        let actions = [Action::Key(Key::LeftShift), Action::Key(Key::A)];
+        assert_eq!(&applied(&actions)[..], &actions);
+    }
+
+    #[test]
+    fn needs_the_modifier_held() {
+        let actions = [Action::Key(Key::LeftControl), Action::Key(Key::Delete)];
+        assert_eq!(&applied(&actions)[..], &actions);
+        let actions = [Action::Shifted(Key::Delete)];
+        assert_eq!(&applied(&actions)[..], &actions);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::switches::SwitchIdentifier;
+
+use super::{action::Action, encoding::ACTION_SIZE, Layer, Layout};
+
+/// 全レイヤーのアクションの表
+///
+/// RAMに置いて書き換えられるようにし、Flashには組み込みのキーマップと違うところだけ保存する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keymap {
+    layers: [[[Action; 12]; 4]; Layer::COUNT],
+}
+
+impl Keymap {
+    // 保存する形式を変えたら上げる
+    pub const VERSION: u8 = 1;
+    // レイヤー、行、列とアクション
+    const ENTRY_SIZE: usize = 3 + ACTION_SIZE;
+
+    pub const fn new(layers: [[[Action; 12]; 4]; Layer::COUNT]) -> Keymap {
+        Keymap { layers }
+    }
+
+    pub fn get(&self, layer: Layer, switch: &SwitchIdentifier) -> Action {
+        self.layers[layer as usize][switch.row as usize][switch.col as usize]
+    }
+
+    pub fn set(&mut self, layer: Layer, switch: &SwitchIdentifier, action: Action) {
+        self.layers[layer as usize][switch.row as usize][switch.col as usize] = action;
+    }
+
+    /// 組み込みのキーマップと違うところを1か所ずつ書き出し、書いた長さを返す
+    ///
+    /// 書き換えていないところはファームウェアを更新すれば新しいキーマップに従う
+    pub fn write_bytes(&self, buf: &mut [u8]) -> usize {
+        buf[0] = Self::VERSION;
+        let mut len = 3;
+        let mut count: u16 = 0;
+        for (l, layer) in Layer::ALL.iter().enumerate() {
+            for row in 0..4 {
+                for col in 0..12 {
+                    let switch = SwitchIdentifier { row, col };
+                    let action = self.get(*layer, &switch);
+                    if action == Layout::DEFAULT_KEYMAP.get(*layer, &switch) {
+                        continue;
+                    }
+                    // 符号にできないアクションは保存しない(組み込みのものに戻る)
+                    let Some(bytes) = action.to_bytes() else {
+                        continue;
+                    };
+                    buf[len..len + 3].copy_from_slice(&[l as u8, row, col]);
+                    buf[len + 3..len + Self::ENTRY_SIZE].copy_from_slice(&bytes);
+                    len += Self::ENTRY_SIZE;
+                    count += 1;
+                }
+            }
+        }
+        buf[1..3].copy_from_slice(&count.to_le_bytes());
+        len
+    }
+
+    /// 読めない書き換え(知らないレイヤーやアクション)は飛ばして組み込みのものを使う
+    pub fn from_bytes(bytes: &[u8]) -> Option<Keymap> {
+        let (&[version, count_lo, count_hi], entries) = bytes.split_first_chunk::<3>()?;
+        if version != Self::VERSION {
+            return None;
+        }
+        let count = u16::from_le_bytes([count_lo, count_hi]) as usize;
+        let entries = entries.get(..count * Self::ENTRY_SIZE)?;
+
+        let mut keymap = Layout::DEFAULT_KEYMAP;
+        for entry in entries.chunks(Self::ENTRY_SIZE) {
+            let (&[l, row, col], action) = entry.split_first_chunk()?;
+            let action = action.try_into().ok()?;
+            let (Some(layer), true, true) = (Layer::ALL.get(l as usize), row < 4, col < 12) else {
+                defmt::warn!(
+                    "Skipping keymap entry out of range ({}, {}, {})",
+                    l,
+                    row,
+                    col
+                );
+                continue;
+            };
+            match Action::from_bytes(action) {
+                Some(action) => keymap.set(*layer, &SwitchIdentifier { row, col }, action),
+                None => defmt::warn!("Skipping unknown keymap entry ({}, {}, {})", l, row, col),
+            }
+        }
+        Some(keymap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustkbd::keyboard::Key;
+
+    use super::*;
+
+    const SWITCH: SwitchIdentifier = SwitchIdentifier { row: 1, col: 2 };
+
+    fn encode(keymap: &Keymap) -> ([u8; 4096], usize) {
+        let mut buf = [0xff; 4096];
+        let len = keymap.write_bytes(&mut buf);
+        (buf, len)
+    }
+
+    #[test]
+    fn stores_only_edited_entries() {
+        let (buf, len) = encode(&Layout::DEFAULT_KEYMAP);
+        assert_eq!(&buf[..len], &[Keymap::VERSION, 0, 0]);
+
+        let mut keymap = Layout::DEFAULT_KEYMAP;
+        keymap.set(Layer::Lower, &SWITCH, Action::Key(Key::F5));
+        let (buf, len) = encode(&keymap);
+        assert_eq!(len, 3 + Keymap::ENTRY_SIZE);
+        assert_eq!(Keymap::from_bytes(&buf[..len]), Some(keymap));
+    }
+
+    #[test]
+    fn skips_unreadable_entries() {
+        let mut keymap = Layout::DEFAULT_KEYMAP;
+        keymap.set(Layer::Lower, &SWITCH, Action::Key(Key::F5));
+        keymap.set(Layer::Raise, &SWITCH, Action::CapsWord);
+        let (mut buf, len) = encode(&keymap);
+        // 1つ目のアクションを知らない種類にする
+        buf[3 + 3] = 0xfe;
+
+        let mut expected = Layout::DEFAULT_KEYMAP;
+        expected.set(Layer::Raise, &SWITCH, Action::CapsWord);
+        assert_eq!(Keymap::from_bytes(&buf[..len]), Some(expected));
+    }
+
+    #[test]
+    fn rejects_other_versions_and_truncated_data() {
+        let mut keymap = Layout::DEFAULT_KEYMAP;
+        keymap.set(Layer::Lower, &SWITCH, Action::Key(Key::F5));
+        let (mut buf, len) = encode(&keymap);
+        assert_eq!(Keymap::from_bytes(&buf[..len - 1]), None);
+        buf[0] = Keymap::VERSION + 1;
+        assert_eq!(Keymap::from_bytes(&buf[..len]), None);
+    }
+}
@@ -0,0 +1,159 @@
+use rp2040_hal::timer::Instant;
+use rustkbd::{keyboard::Key, Vec};
+
+use super::Action;
+
+/// リーダーキーのあとに続けて押すキーと、そのときのアクション
+#[derive(Debug, Clone, Copy)]
+pub struct LeaderSequence {
+    pub keys: &'static [Key],
+    pub action: Action,
+}
+
+pub(super) enum Match {
+    /// まだ続きがある
+    Partial,
+    Complete(Action),
+    None,
+}
+
+/// リーダーキーのあとに押したキー
+#[derive(Debug, Clone)]
+pub(super) struct PendingLeader {
+    keys: Vec<Key, 8>,
+    pub changed_at: Instant,
+}
+
+impl PendingLeader {
+    pub fn new(now: Instant) -> PendingLeader {
+        PendingLeader {
+            keys: Vec::new(),
+            changed_at: now,
+        }
+    }
+
+    pub fn push(&mut self, key: Key, sequences: &[LeaderSequence], now: Instant) -> Match {
+        if self.keys.push(key).is_err() {
+            return Match::None;
+        }
+        self.changed_at = now;
+
+        let mut partial = false;
+        for sequence in sequences {
+            if sequence.keys == self.keys.as_slice() {
+                return Match::Complete(sequence.action);
+            }
+            partial |= sequence.keys.starts_with(&self.keys);
+        }
+        if partial {
+            Match::Partial
+        } else {
+            Match::None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        layout::{
+            testing::{at, layout, DEPTHS},
+            Layout,
+        },
+        switches::SwitchIdentifier,
+    };
+
+    const SEQUENCES: [LeaderSequence; 2] = [
+        LeaderSequence {
+            keys: &[Key::G, Key::P],
+            action: Action::ToggleGamepad,
+        },
+        LeaderSequence {
+            keys: &[Key::G, Key::G, Key::G],
+            action: Action::Bootloader,
+        },
+    ];
+
+    const RAISE: SwitchIdentifier = SwitchIdentifier { row: 3, col: 8 };
+    const A: SwitchIdentifier = SwitchIdentifier { row: 1, col: 1 };
+    const G: SwitchIdentifier = SwitchIdentifier { row: 1, col: 5 };
+    const P: SwitchIdentifier = SwitchIdentifier { row: 0, col: 10 };
+
+    #[test]
+    fn matches_sequences_key_by_key() {
+        let mut leader = PendingLeader::new(at(0));
+        assert!(matches!(
+            leader.push(Key::G, &SEQUENCES, at(10)),
+            Match::Partial
+        ));
+        assert_eq!(leader.changed_at, at(10));
+        assert!(matches!(
+            leader.push(Key::P, &SEQUENCES, at(20)),
+            Match::Complete(Action::ToggleGamepad)
+        ));
+
+        let mut leader = PendingLeader::new(at(0));
+        leader.push(Key::G, &SEQUENCES, at(10));
+        assert!(matches!(
+            leader.push(Key::G, &SEQUENCES, at(20)),
+            Match::Partial
+        ));
+        assert!(matches!(
+            leader.push(Key::G, &SEQUENCES, at(30)),
+            Match::Complete(Action::Bootloader)
+        ));
+    }
+
+    #[test]
+    fn rejects_keys_outside_sequences() {
+        let mut leader = PendingLeader::new(at(0));
+        assert!(matches!(
+            leader.push(Key::X, &SEQUENCES, at(10)),
+            Match::None
+        ));
+        let mut leader = PendingLeader::new(at(0));
+        leader.push(Key::G, &SEQUENCES, at(10));
+        assert!(matches!(
+            leader.push(Key::X, &SEQUENCES, at(20)),
+            Match::None
+        ));
+    }
+
+    // QWERTYのRaise + Aでリーダーキーを押して離す
+    fn layout_after_leader() -> Layout {
+        let mut layout = layout(Layout::DEFAULT_KEYMAP);
+        layout.update(&[RAISE], &DEPTHS, at(0));
+        layout.update(&[RAISE, A], &DEPTHS, at(10));
+        layout.update(&[], &DEPTHS, at(20));
+        layout
+    }
+
+    #[test]
+    fn layout_fires_the_sequence_instead_of_the_keys() {
+        let mut layout = layout_after_leader();
+        assert!(layout.update(&[G], &DEPTHS, at(100)).is_empty());
+        layout.update(&[], &DEPTHS, at(150));
+        assert_eq!(
+            &layout.update(&[P], &DEPTHS, at(200))[..],
+            &[Action::ToggleGamepad]
+        );
+
+        // シーケンスが終わったら普通に打てる
+        layout.update(&[], &DEPTHS, at(300));
+        assert_eq!(
+            &layout.update(&[G], &DEPTHS, at(400))[..],
+            &[Action::Key(Key::G)]
+        );
+    }
+
+    #[test]
+    fn layout_cancels_the_leader_after_the_timeout() {
+        let mut layout = layout_after_leader();
+        layout.update(&[], &DEPTHS, at(1_020));
+        assert_eq!(
+            &layout.update(&[G], &DEPTHS, at(1_100))[..],
+            &[Action::Key(Key::G)]
+        );
+    }
+}
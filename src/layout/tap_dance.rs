@@ -0,0 +1,110 @@
+use rp2040_hal::timer::Instant;
+use rustkbd::keyboard::Key;
+
+use super::{Hold, Trigger};
+
+/// 続けてタップした回数でアクションが変わるキー
+#[derive(Debug, Clone, Copy)]
+pub struct TapDance {
+    /// n回目で決まったときのアクション
+    pub actions: &'static [TapDanceAction],
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TapDanceAction {
+    pub tap: Key,
+    /// Noneなら押し続けている間`tap`のキーを押したままにする
+    pub hold: Option<Hold>,
+}
+
+/// まだ回数が決まっていないタップダンス
+#[derive(Debug, Clone, Copy)]
+pub(super) struct PendingTapDance {
+    pub trigger: Trigger,
+    pub index: u8,
+    pub count: u8,
+    pub pressed: bool,
+    // 最後に押したか離した時刻
+    pub changed_at: Instant,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        layout::{
+            testing::{at, layout, DEPTHS},
+            Action, Layer, Layout,
+        },
+        switches::SwitchIdentifier,
+    };
+
+    // Defaultレイヤーの'
+    const QUOTE: SwitchIdentifier = SwitchIdentifier { row: 1, col: 11 };
+
+    #[test]
+    fn taps_once_after_the_tapping_term() {
+        let mut layout = layout(Layout::DEFAULT_KEYMAP);
+        layout.update(&[QUOTE], &DEPTHS, at(0));
+        assert!(layout.update(&[], &DEPTHS, at(50)).is_empty());
+        assert!(layout.update(&[], &DEPTHS, at(249)).is_empty());
+        assert_eq!(
+            &layout.update(&[], &DEPTHS, at(250))[..],
+            &[Action::Key(Key::Quote)]
+        );
+    }
+
+    #[test]
+    fn taps_the_last_action_without_waiting() {
+        let mut layout = layout(Layout::DEFAULT_KEYMAP);
+        layout.update(&[QUOTE], &DEPTHS, at(0));
+        layout.update(&[], &DEPTHS, at(50));
+        layout.update(&[QUOTE], &DEPTHS, at(100));
+        assert_eq!(
+            &layout.update(&[], &DEPTHS, at(150))[..],
+            &[Action::Key(Key::DoubleQuote)]
+        );
+    }
+
+    #[test]
+    fn holds_after_the_tapping_term() {
+        let mut layout = layout(Layout::DEFAULT_KEYMAP);
+        layout.update(&[QUOTE], &DEPTHS, at(0));
+        assert_eq!(
+            &layout.update(&[QUOTE], &DEPTHS, at(200))[..],
+            &[Action::Layer(Layer::Raise)]
+        );
+        assert_eq!(layout.layer(), Layer::Raise);
+    }
+
+    #[test]
+    fn does_nothing_for_unknown_tap_dances() {
+        let mut keymap = Layout::DEFAULT_KEYMAP;
+        keymap.set(Layer::Default, &QUOTE, Action::TapDance(99));
+        let mut layout = layout(keymap);
+        layout.update(&[QUOTE], &DEPTHS, at(0));
+        assert!(layout.update(&[QUOTE], &DEPTHS, at(300)).is_empty());
+        assert!(layout.update(&[], &DEPTHS, at(350)).is_empty());
+    }
+
+    #[test]
+    fn rolls_over_to_the_next_key_as_a_tap() {
+        let mut layout = layout(Layout::DEFAULT_KEYMAP);
+        let s = SwitchIdentifier { row: 1, col: 2 };
+        layout.update(&[QUOTE], &DEPTHS, at(0));
+        assert_eq!(
+            &layout.update(&[QUOTE, s], &DEPTHS, at(30))[..],
+            &[Action::Key(Key::Quote), Action::Key(Key::S)]
+        );
+        assert_eq!(layout.layer(), Layer::Default);
+        assert_eq!(
+            &layout.update(&[s], &DEPTHS, at(60))[..],
+            &[Action::Key(Key::S)]
+        );
+        // タッピングタームを過ぎてもホールドにならない
+        assert_eq!(
+            &layout.update(&[s], &DEPTHS, at(300))[..],
+            &[Action::Key(Key::S)]
+        );
+    }
+}
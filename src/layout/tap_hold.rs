@@ -0,0 +1,171 @@
+use fugit::MicrosDurationU32;
+use rp2040_hal::timer::Instant;
+use rustkbd::{keyboard::Key, Vec};
+
+use super::{Action, Event, Layer, Trigger};
+
+/// タップしたときと押し続けたときで別のアクションになるキー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TapHold {
+    pub tap: Key,
+    pub hold: Hold,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hold {
+    /// 修飾キーなど
+    Key(Key),
+    Layer(Layer),
+}
+
+impl From<Hold> for Action {
+    fn from(hold: Hold) -> Self {
+        match hold {
+            Hold::Key(key) => Action::Key(key),
+            Hold::Layer(layer) => Action::Layer(layer),
+        }
+    }
+}
+
+/// タッピングタームより前にどうなったらホールドとみなすか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapHoldMode {
+    /// タッピングタームを過ぎるまでは常にタップ
+    TappingTerm,
+    /// 他のキーを押したらホールド
+    HoldOnOtherKeyPress,
+    /// 他のキーを押して離したらホールド
+    PermissiveHold,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TapHoldConfig {
+    pub tapping_term: MicrosDurationU32,
+    pub mode: TapHoldMode,
+}
+
+/// タップかホールドか決まっていないキー
+///
+/// 決まるまでに起きたイベントは溜めておき、決まってから順に処理し直す
+#[derive(Debug, Clone)]
+pub(super) struct PendingTapHold {
+    pub trigger: Trigger,
+    pub tap_hold: TapHold,
+    pub pressed_at: Instant,
+    pub events: Vec<Event, 16>,
+}
+
+impl PendingTapHold {
+    /// `event`を溜めて、それでホールドに決まればtrue
+    pub fn push(&mut self, event: Event, mode: TapHoldMode) -> bool {
+        self.events.push(event).ok();
+        match (mode, event) {
+            (TapHoldMode::HoldOnOtherKeyPress, Event::Pressed(_)) => true,
+            (TapHoldMode::PermissiveHold, Event::Released(switch)) => {
+                self.events.contains(&Event::Pressed(switch))
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_expired(&self, now: Instant, config: &TapHoldConfig) -> bool {
+        now - self.pressed_at >= config.tapping_term
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        layout::{
+            testing::{at, layout, DEPTHS},
+            Layout,
+        },
+        switches::SwitchIdentifier,
+    };
+
+    const TAP_HOLD: Trigger = Trigger::Switch(SwitchIdentifier { row: 3, col: 7 });
+    const OTHER: Trigger = Trigger::Switch(SwitchIdentifier { row: 1, col: 1 });
+
+    fn pending() -> PendingTapHold {
+        PendingTapHold {
+            trigger: TAP_HOLD,
+            tap_hold: TapHold {
+                tap: Key::Space,
+                hold: Hold::Layer(Layer::Lower),
+            },
+            pressed_at: at(1),
+            events: Vec::new(),
+        }
+    }
+
+    // 他のキーを押して離したとき、どこでホールドに決まるか
+    fn resolved_at(mode: TapHoldMode) -> Option<usize> {
+        let mut pending = pending();
+        let events = [Event::Pressed(OTHER), Event::Released(OTHER)];
+        let resolved = events.iter().position(|event| pending.push(*event, mode));
+        assert_eq!(
+            &pending.events[..],
+            &events[..resolved.map_or(2, |i| i + 1)]
+        );
+        resolved
+    }
+
+    #[test]
+    fn resolves_on_other_keys_by_mode() {
+        assert_eq!(resolved_at(TapHoldMode::TappingTerm), None);
+        assert_eq!(resolved_at(TapHoldMode::HoldOnOtherKeyPress), Some(0));
+        assert_eq!(resolved_at(TapHoldMode::PermissiveHold), Some(1));
+    }
+
+    #[test]
+    fn permissive_hold_ignores_keys_pressed_before() {
+        let mut pending = pending();
+        assert!(!pending.push(Event::Released(OTHER), TapHoldMode::PermissiveHold));
+    }
+
+    #[test]
+    fn expires_after_tapping_term() {
+        let config = TapHoldConfig {
+            tapping_term: MicrosDurationU32::millis(200),
+            mode: TapHoldMode::TappingTerm,
+        };
+        let pending = pending();
+        assert!(!pending.is_expired(at(200), &config));
+        assert!(pending.is_expired(at(201), &config));
+    }
+
+    #[test]
+    fn holds_as_key_or_layer() {
+        assert_eq!(
+            Action::from(Hold::Key(Key::LeftControl)),
+            Action::Key(Key::LeftControl)
+        );
+        assert_eq!(
+            Action::from(Hold::Layer(Layer::Lower)),
+            Action::Layer(Layer::Lower)
+        );
+    }
+
+    #[test]
+    fn layout_taps_or_holds_the_thumb_key() {
+        let mut layout = layout(Layout::DEFAULT_KEYMAP);
+        let thumb = SwitchIdentifier { row: 3, col: 7 };
+
+        assert!(layout.update(&[thumb], &DEPTHS, at(0)).is_empty());
+        assert_eq!(
+            &layout.update(&[], &DEPTHS, at(100))[..],
+            &[Action::Key(Key::Space)]
+        );
+
+        layout.update(&[thumb], &DEPTHS, at(1_000));
+        assert!(layout.update(&[thumb], &DEPTHS, at(1_199)).is_empty());
+        assert_eq!(
+            &layout.update(&[thumb], &DEPTHS, at(1_200))[..],
+            &[Action::Layer(Layer::Lower)]
+        );
+        assert_eq!(layout.layer(), Layer::Lower);
+        assert!(layout.update(&[], &DEPTHS, at(1_300)).is_empty());
+        assert_eq!(layout.layer(), Layer::Default);
+    }
+}
@@ -0,0 +1,30 @@
+//! ファームウェア本体(`main.rs`)以外はホストでもテストできるようにライブラリにしておく
+#![cfg_attr(not(test), no_std)]
+
+pub mod controller;
+pub mod drawing;
+pub mod gamepad;
+pub mod layout;
+pub mod macros;
+pub mod mouse;
+pub mod settings;
+pub mod storage;
+pub mod switches;
+pub mod unicode;
+pub mod usb;
+
+// ホストでのテストではdefmtのログは捨てる
+#[cfg(test)]
+mod test_logger {
+    #[defmt::global_logger]
+    struct Logger;
+
+    unsafe impl defmt::Logger for Logger {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
+    }
+
+    defmt::timestamp!("{=u32}", 0);
+}
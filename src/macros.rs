@@ -0,0 +1,254 @@
+use fugit::MicrosDurationU32;
+use rp2040_hal::timer::Instant;
+use rustkbd::{keyboard::Key, Vec};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacroStep {
+    Press(Key),
+    Release(Key),
+    /// 押して離す
+    Tap(Key),
+    /// ASCIIの文字列を1文字ずつ打つ(それ以外の文字は飛ばす)
+    Text(&'static str),
+    /// ミリ秒
+    Delay(u32),
+}
+
+// ヒープがないのでそのまま持つ
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+enum Steps {
+    Static(&'static [MacroStep]),
+    // Unicodeの入力など、押したときに作るもの
+    Owned(Vec<MacroStep, 24>),
+}
+
+/// マクロを1レポートずつ進める
+///
+/// レポートがホストに読まれてから`advance`するので、途中のレポートが上書きされて消えることはない
+#[derive(Debug, Clone)]
+pub struct MacroPlayer {
+    steps: Steps,
+    index: usize,
+    // Textの何文字目か
+    offset: usize,
+    keys: Vec<Key, 6>,
+    // 次のレポートで離すキー
+    releasing: Vec<Key, 2>,
+    wait_until: Option<Instant>,
+}
+
+impl MacroPlayer {
+    pub fn new(steps: &'static [MacroStep]) -> MacroPlayer {
+        Self::with_steps(Steps::Static(steps))
+    }
+
+    pub fn from_steps(steps: Vec<MacroStep, 24>) -> MacroPlayer {
+        Self::with_steps(Steps::Owned(steps))
+    }
+
+    fn with_steps(steps: Steps) -> MacroPlayer {
+        MacroPlayer {
+            steps,
+            index: 0,
+            offset: 0,
+            keys: Vec::new(),
+            releasing: Vec::new(),
+            wait_until: None,
+        }
+    }
+
+    /// いま送るレポートのキー
+    pub fn keys(&self) -> &[Key] {
+        &self.keys
+    }
+
+    /// 次のレポートに進める。最後まで送り終えたらfalse
+    pub fn advance(&mut self, now: Instant) -> bool {
+        if let Some(wait_until) = self.wait_until {
+            if now < wait_until {
+                return true;
+            }
+            self.wait_until = None;
+        }
+        if !self.releasing.is_empty() {
+            let releasing = core::mem::take(&mut self.releasing);
+            self.keys.retain(|key| !releasing.contains(key));
+            return true;
+        }
+
+        let steps = match &self.steps {
+            Steps::Static(steps) => steps,
+            Steps::Owned(steps) => steps.as_slice(),
+        };
+        let Some(&step) = steps.get(self.index) else {
+            return false;
+        };
+        match step {
+            MacroStep::Press(key) => {
+                self.keys.push(key).ok();
+            }
+            MacroStep::Release(key) => self.keys.retain(|k| *k != key),
+            MacroStep::Tap(key) => self.tap(&[key]),
+            MacroStep::Text(text) => {
+                let c = text.as_bytes().get(self.offset).copied().map(char::from);
+                self.offset += 1;
+                match c.and_then(char_key) {
+                    Some((key, true)) => self.tap(&[Key::LeftShift, key]),
+                    Some((key, false)) => self.tap(&[key]),
+                    None => {}
+                }
+                if self.offset < text.len() {
+                    return true;
+                }
+                self.offset = 0;
+            }
+            MacroStep::Delay(ms) => {
+                self.wait_until = Some(now + MicrosDurationU32::millis(ms));
+            }
+        }
+        self.index += 1;
+        true
+    }
+
+    fn tap(&mut self, keys: &[Key]) {
+        for key in keys {
+            self.keys.push(*key).ok();
+            self.releasing.push(*key).ok();
+        }
+    }
+}
+
+pub const LETTERS: [Key; 26] = [
+    Key::A,
+    Key::B,
+    Key::C,
+    Key::D,
+    Key::E,
+    Key::F,
+    Key::G,
+    Key::H,
+    Key::I,
+    Key::J,
+    Key::K,
+    Key::L,
+    Key::M,
+    Key::N,
+    Key::O,
+    Key::P,
+    Key::Q,
+    Key::R,
+    Key::S,
+    Key::T,
+    Key::U,
+    Key::V,
+    Key::W,
+    Key::X,
+    Key::Y,
+    Key::Z,
+];
+pub const DIGITS: [Key; 10] = [
+    Key::Digit0,
+    Key::Digit1,
+    Key::Digit2,
+    Key::Digit3,
+    Key::Digit4,
+    Key::Digit5,
+    Key::Digit6,
+    Key::Digit7,
+    Key::Digit8,
+    Key::Digit9,
+];
+
+/// USキーボードで文字を打つキーと、Shiftが要るかどうか
+pub fn char_key(c: char) -> Option<(Key, bool)> {
+    let key = match c {
+        'a'..='z' => LETTERS[c as usize - 'a' as usize],
+        'A'..='Z' => return Some((LETTERS[c as usize - 'A' as usize], true)),
+        '0'..='9' => DIGITS[c as usize - '0' as usize],
+        ' ' => Key::Space,
+        '\n' => Key::Enter,
+        '\t' => Key::Tab,
+        '-' => Key::Minus,
+        '=' => Key::Equal,
+        '[' => Key::LeftBracket,
+        ']' => Key::RightBracket,
+        '\\' => Key::Backslash,
+        ';' => Key::Semicolon,
+        '\'' => Key::Quote,
+        '`' => Key::Grave,
+        ',' => Key::Comma,
+        '.' => Key::Period,
+        '/' => Key::Slash,
+        // 記号のキーはShift込み
+        '!' => Key::Exclamation,
+        '@' => Key::At,
+        '#' => Key::Hash,
+        '$' => Key::Dollar,
+        '%' => Key::Percent,
+        '^' => Key::Caret,
+        '&' => Key::Ampersand,
+        '*' => Key::Asterisk,
+        '(' => Key::LeftParenthesis,
+        ')' => Key::RightParenthesis,
+        '_' => Key::Underscore,
+        '+' => Key::Plus,
+        '{' => Key::LeftCurlyBrace,
+        '}' => Key::RightCurlyBrace,
+        '|' => Key::Pipe,
+        ':' => Key::Colon,
+        '"' => Key::DoubleQuote,
+        '~' => Key::Tilde,
+        '<' => Key::LessThan,
+        '>' => Key::GreaterThan,
+        '?' => Key::Question,
+        _ => return None,
+    };
+    Some((key, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1msごとにレポートを送ったとき、終わるまでに送るレポート
+    fn play(steps: &'static [MacroStep]) -> std::vec::Vec<Vec<Key, 6>> {
+        let mut player = MacroPlayer::new(steps);
+        let mut reports = std::vec::Vec::new();
+        let mut now = 0;
+        while player.advance(Instant::from_ticks(now * 1_000)) {
+            reports.push(Vec::from_slice(player.keys()).unwrap());
+            now += 1;
+        }
+        reports
+    }
+
+    #[test]
+    fn types_text_one_character_per_tap() {
+        let reports = play(&[MacroStep::Text("aB\n")]);
+        let expected: [&[Key]; 6] = [
+            &[Key::A],
+            &[],
+            &[Key::LeftShift, Key::B],
+            &[],
+            &[Key::Enter],
+            &[],
+        ];
+        assert_eq!(reports.len(), expected.len());
+        for (report, keys) in reports.iter().zip(expected) {
+            assert_eq!(&report[..], keys);
+        }
+    }
+
+    #[test]
+    fn holds_keys_through_a_delay() {
+        let reports = play(&[
+            MacroStep::Press(Key::LeftGui),
+            MacroStep::Delay(10),
+            MacroStep::Release(Key::LeftGui),
+        ]);
+        assert_eq!(reports.len(), 12);
+        assert!(reports[..11].iter().all(|keys| keys[..] == [Key::LeftGui]));
+        assert!(reports[11].is_empty());
+    }
+}
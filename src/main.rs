@@ -2,40 +2,46 @@
 #![no_main]
 
 use core::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     sync::atomic::{AtomicBool, Ordering},
 };
 
-use cortex_m::delay::Delay;
+use cortex_m::{delay::Delay, peripheral::SCB};
 use critical_section::Mutex;
 use defmt_rtt as _;
-use drawing::Display;
 use fugit::{ExtU32, MicrosDurationU32, RateExtU32};
 use hal::{
     adc::AdcPin,
-    clocks, entry,
+    clocks,
+    dma::{Channel, DMAExt, CH0},
+    entry,
     gpio::{Pins, PullUp},
     multicore::{Multicore, Stack},
     pac::{interrupt, CorePeripherals, Interrupt, Peripherals, NVIC},
     sio::Spinlock0,
-    timer::{Alarm, Alarm0, Alarm1, Instant},
+    timer::{Alarm, Alarm0, Alarm1, Alarm2, Instant},
     usb::UsbBus,
     Adc, Clock, Sio, Timer, Watchdog, I2C,
 };
-use layout::Layout;
-use panic_probe as _;
-use rp2040_hal as hal;
-use rustkbd::{
-    keyboard::Controller,
+use necoboard::{
+    controller::Controller,
+    drawing::Display,
+    gamepad::{Axis, AxisKey, Curve, Gamepad},
+    layout::{AutoShift, Layout, TapHoldConfig, TapHoldMode},
+    mouse::MouseKeys,
+    settings::Settings,
+    storage,
+    switches::{
+        Actuation, AnyFilter, Frame, FrameSampler, KalmanFilter, KeyMatrix, RapidTrigger,
+        ScanSequencer, SharedFrame, SwitchIdentifier,
+    },
     usb::{DeviceInfo, UsbCommunicator},
 };
-use switches::KeyMatrix;
+use panic_probe as _;
+use rp2040_hal as hal;
+use rustkbd::keyboard::KeySwitches as _;
 use usb_device::class_prelude::UsbBusAllocator;
 
-mod drawing;
-mod layout;
-mod switches;
-
 /// The linker will place this boot block at the start of our program image. We
 /// need this to help the ROM bootloader get our code up and running.
 /// Note: This boot block is not necessary when using a rp-hal based BSP
@@ -44,11 +50,16 @@ mod switches;
 #[used]
 pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_GENERIC_03H;
 
-type KeyboardType =
-    Controller<2, 12, UsbCommunicator<'static, UsbBus>, KeyMatrix<Delay, 4, 4, 12>, Layout>;
+type KeyboardType = Controller<'static, UsbBus, FrameSampler<4, 12>, SwitchFilter>;
+// ボードのノイズに合わせてswitch_filterで選ぶ(全キー同じならKalmanFilterなどを直接使ってもよい)
+type SwitchFilter = AnyFilter;
+type SequencerType = ScanSequencer<Channel<CH0>, 4, 4, 12>;
 static mut KEYBOARD: Mutex<RefCell<Option<KeyboardType>>> = Mutex::new(RefCell::new(None));
 static mut ALARM0: Mutex<RefCell<Option<Alarm0>>> = Mutex::new(RefCell::new(None));
 static mut ALARM1: Mutex<RefCell<Option<Alarm1>>> = Mutex::new(RefCell::new(None));
+static mut ALARM2: Mutex<RefCell<Option<Alarm2>>> = Mutex::new(RefCell::new(None));
+static mut SEQUENCER: Mutex<RefCell<Option<SequencerType>>> = Mutex::new(RefCell::new(None));
+static FRAME: SharedFrame<4, 12> = Mutex::new(Cell::new(Frame::EMPTY));
 static mut WATCHDOG: Mutex<RefCell<Option<Watchdog>>> = Mutex::new(RefCell::new(None));
 static mut TIMER: Mutex<RefCell<Option<Timer>>> = Mutex::new(RefCell::new(None));
 static SLEEP_MODE: AtomicBool = AtomicBool::new(false);
@@ -56,9 +67,94 @@ static SLEEP_MODE: AtomicBool = AtomicBool::new(false);
 static mut LAST_KEYS_ON: Mutex<RefCell<Instant>> = Mutex::new(RefCell::new(Instant::from_ticks(0)));
 
 const USB_SEND_INTERVAL: MicrosDurationU32 = MicrosDurationU32::millis(10);
+// センサーの値をホストに送る間隔(USB_SEND_INTERVALより短くはできない)
+const SENSOR_STREAM_INTERVAL: MicrosDurationU32 = MicrosDurationU32::millis(10);
 const SWITCH_SCAN_INTERVAL: MicrosDurationU32 = MicrosDurationU32::millis(5);
 const SLEEP_MODE_INTERVAL: MicrosDurationU32 = MicrosDurationU32::secs(10);
 const XTAL_FREQ_HZ: u32 = 12_000_000;
+// 起動時にこのキーを押しているとキャリブレーションを行う
+const CALIBRATION_SWITCH: SwitchIdentifier = SwitchIdentifier { row: 0, col: 0 };
+// ホームポジションは深め、修飾キーは浅めで反応させる
+const ACTUATIONS: [(SwitchIdentifier, Actuation); 10] = {
+    const HOME: Actuation = Actuation {
+        press: 0.65,
+        release: 0.5,
+    };
+    const MODIFIER: Actuation = Actuation {
+        press: 0.4,
+        release: 0.25,
+    };
+    [
+        (SwitchIdentifier { row: 1, col: 1 }, HOME),
+        (SwitchIdentifier { row: 1, col: 2 }, HOME),
+        (SwitchIdentifier { row: 1, col: 3 }, HOME),
+        (SwitchIdentifier { row: 1, col: 4 }, HOME),
+        (SwitchIdentifier { row: 1, col: 7 }, HOME),
+        (SwitchIdentifier { row: 1, col: 8 }, HOME),
+        (SwitchIdentifier { row: 1, col: 9 }, HOME),
+        (SwitchIdentifier { row: 1, col: 10 }, HOME),
+        (SwitchIdentifier { row: 1, col: 0 }, MODIFIER),
+        (SwitchIdentifier { row: 2, col: 0 }, MODIFIER),
+    ]
+};
+// ラピッドトリガーを使う場合は Some(RapidTrigger { press_delta: 10.0, release_delta: 10.0 }) などにする
+const RAPID_TRIGGER: Option<RapidTrigger> = None;
+// 1℃あたりのセンサーの値の変化。基板ごとに測って決める(Noneなら温度では補正しない)
+const TEMPERATURE_COEFFICIENT: Option<f32> = None;
+
+const TAP_HOLD: TapHoldConfig = TapHoldConfig {
+    tapping_term: MicrosDurationU32::millis(200),
+    mode: TapHoldMode::PermissiveHold,
+};
+
+// Adjust + Sでオートシフトを有効にしたとき、ここまで押し込んだキーをShift付きで送る
+const AUTO_SHIFT: AutoShift = AutoShift::Depth(0.9);
+
+// ゲームパッドモードではWASDを左スティックにする
+const GAMEPAD: Gamepad = Gamepad {
+    keys: &[
+        AxisKey {
+            switch: SwitchIdentifier { row: 0, col: 2 },
+            axis: Axis::Y,
+            negative: true,
+        },
+        AxisKey {
+            switch: SwitchIdentifier { row: 1, col: 1 },
+            axis: Axis::X,
+            negative: true,
+        },
+        AxisKey {
+            switch: SwitchIdentifier { row: 1, col: 2 },
+            axis: Axis::Y,
+            negative: false,
+        },
+        AxisKey {
+            switch: SwitchIdentifier { row: 1, col: 3 },
+            axis: Axis::X,
+            negative: false,
+        },
+    ],
+    dead_zone: 0.1,
+    saturation: 0.9,
+    curve: Curve::Quadratic,
+};
+
+// 軽く押すと細かく、深く押すと速く動く。押し続けると1秒かけて加速する
+const MOUSE_KEYS: MouseKeys = MouseKeys {
+    dead_zone: 0.3,
+    saturation: 0.9,
+    curve: Curve::Quadratic,
+    cursor_speed: (100.0, 1500.0),
+    wheel_speed: (4.0, 40.0),
+    initial_scale: 0.5,
+    acceleration_time: MicrosDurationU32::millis(1000),
+    acceleration_curve: Curve::Quadratic,
+};
+
+/// キーごとのフィルタ。スパイク状のノイズが乗るキーはAnyFilter::Medianなどにする
+fn switch_filter(_switch: SwitchIdentifier) -> SwitchFilter {
+    AnyFilter::Kalman(KalmanFilter::new(2.0, 10.0))
+}
 
 static mut CORE1_STACK: Stack<4096> = Stack::new();
 
@@ -100,10 +196,14 @@ fn main() -> ! {
     let mut alarm1 = timer.alarm_1().unwrap();
     alarm1.schedule(SWITCH_SCAN_INTERVAL).unwrap();
     alarm1.enable_interrupt();
+    let mut alarm2 = timer.alarm_2().unwrap();
+    alarm2.schedule(MicrosDurationU32::micros(0)).unwrap();
+    alarm2.enable_interrupt();
     critical_section::with(|cs| unsafe {
         LAST_KEYS_ON.borrow(cs).replace(timer.get_counter());
         ALARM0.borrow(cs).replace(Some(alarm0));
         ALARM1.borrow(cs).replace(Some(alarm1));
+        ALARM2.borrow(cs).replace(Some(alarm2));
         TIMER.borrow(cs).replace(Some(timer));
     });
     let usb_bus = UsbBusAllocator::new(UsbBus::new(
@@ -129,7 +229,8 @@ fn main() -> ! {
     );
     let mut display = Display::new(i2c);
 
-    let key_matrix = KeyMatrix::new(
+    let dma = pac.DMA.split(&mut pac.RESETS);
+    let sequencer = ScanSequencer::new(
         [
             pins.gpio18.reconfigure().into_dyn_pin(),
             pins.gpio19.reconfigure().into_dyn_pin(),
@@ -145,10 +246,45 @@ fn main() -> ! {
         pins.gpio7.reconfigure().into_dyn_pin(),
         pins.gpio29.reconfigure().into_dyn_pin(),
         pins.gpio28.reconfigure().into_dyn_pin(),
-        Adc::new(pac.ADC, &mut pac.RESETS),
+        cortex_m::singleton!(: Adc = Adc::new(pac.ADC, &mut pac.RESETS)).unwrap(),
         AdcPin::new(pins.gpio26).unwrap(),
-        Delay::new(core.SYST, clocks.system_clock.freq().to_Hz()),
+        dma.ch0,
+        cortex_m::singleton!(: [u16; 48] = [0; 48]).unwrap(),
+        clocks.system_clock.freq().to_MHz(),
+        &FRAME,
     );
+    critical_section::with(|cs| unsafe {
+        SEQUENCER.borrow(cs).replace(Some(sequencer));
+    });
+    unsafe {
+        // キャリブレーションの判定より前にスキャンを始めておく
+        NVIC::unmask(Interrupt::TIMER_IRQ_2);
+    }
+
+    let mut delay = Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
+    let mut key_matrix = KeyMatrix::new(FrameSampler::new(&FRAME), switch_filter);
+    if let Some(calibration) = storage::load() {
+        key_matrix.set_calibration(calibration);
+    } else {
+        defmt::warn!("No calibration found, using the default threshold");
+    }
+    // フィルタとバッファが落ち着くまで何回かスキャンする
+    let mut switches = Default::default();
+    for _ in 0..3 {
+        delay.delay_ms(SWITCH_SCAN_INTERVAL.to_millis());
+        switches = key_matrix.scan();
+    }
+    if switches.contains(&CALIBRATION_SWITCH) {
+        while key_matrix.scan().contains(&CALIBRATION_SWITCH) {
+            delay.delay_ms(SWITCH_SCAN_INTERVAL.to_millis());
+        }
+        key_matrix.start_calibration();
+    }
+    for (switch, actuation) in ACTUATIONS {
+        key_matrix.set_actuation(switch, actuation);
+    }
+    key_matrix.set_rapid_trigger(RAPID_TRIGGER);
+    key_matrix.set_temperature_coefficient(TEMPERATURE_COEFFICIENT);
 
     let device_info = DeviceInfo {
         manufacturer: "necocen",
@@ -158,10 +294,19 @@ fn main() -> ! {
         serial_number: "17",
     };
 
+    let settings: Settings = storage::load().unwrap_or_default();
     let keyboard = Controller::new(
-        UsbCommunicator::new(device_info, USB_BUS.as_ref().unwrap()),
+        UsbCommunicator::new(device_info, USB_BUS.as_ref().unwrap(), settings.gamepad),
         key_matrix,
-        Layout::default(),
+        Layout::new(
+            storage::load().unwrap_or(Layout::DEFAULT_KEYMAP),
+            TAP_HOLD,
+            AUTO_SHIFT,
+        ),
+        settings,
+        GAMEPAD,
+        MOUSE_KEYS,
+        SENSOR_STREAM_INTERVAL,
     );
 
     watchdog.pause_on_debug(true);
@@ -180,32 +325,70 @@ fn main() -> ! {
 
     core1
         .spawn(unsafe { &mut CORE1_STACK.mem }, move || loop {
+            storage::park_core1_if_requested();
             if SLEEP_MODE.load(Ordering::Relaxed) {
                 // スリープモードに入った最初のフレームでは黒く塗る
                 display.draw_sleep();
                 while SLEEP_MODE.load(Ordering::Relaxed) {
+                    storage::park_core1_if_requested();
                     core::hint::spin_loop()
                 }
             }
 
-            let values = {
+            let (values, thresholds, health, base_layout, show_diagnostics) = {
                 let _lock = Spinlock0::claim();
                 critical_section::with(|cs| unsafe {
-                    KEYBOARD
-                        .borrow(cs)
-                        .borrow()
-                        .as_ref()
-                        .unwrap()
-                        .key_switches
-                        .values()
+                    let keyboard = KEYBOARD.borrow(cs).borrow();
+                    let keyboard = keyboard.as_ref().unwrap();
+                    let key_switches = &keyboard.key_switches;
+                    (
+                        key_switches.values(),
+                        key_switches.press_thresholds(),
+                        key_switches.health(),
+                        keyboard.layout.base_layout(),
+                        keyboard.shows_diagnostics(),
+                    )
                 })
             };
-            display.draw(&values);
+            if show_diagnostics {
+                display.draw_diagnostics(&health);
+            } else {
+                display.draw(&values, &thresholds, base_layout);
+            }
         })
         .unwrap();
 
     loop {
         cortex_m::asm::wfi();
+
+        let (calibration, changed_settings, keymap) = {
+            let _lock = Spinlock0::claim();
+            critical_section::with(|cs| unsafe {
+                let mut keyboard = KEYBOARD.borrow(cs).borrow_mut();
+                let keyboard = keyboard.as_mut().unwrap();
+                (
+                    keyboard.key_switches.take_finished_calibration(),
+                    keyboard.take_changed_settings(),
+                    keyboard.layout.take_keymap_to_save(),
+                )
+            })
+        };
+        if let Some(calibration) = calibration {
+            defmt::info!("Saving calibration");
+            storage::save(&calibration);
+        }
+        if let Some(keymap) = keymap {
+            defmt::info!("Saving keymap");
+            storage::save(&keymap);
+        }
+        if let Some(changed_settings) = changed_settings {
+            defmt::info!("Saving settings");
+            storage::save(&changed_settings);
+            // ゲームパッドのインターフェースを増減するには列挙し直す
+            if changed_settings.gamepad != settings.gamepad {
+                SCB::sys_reset();
+            }
+        }
     }
 }
 
@@ -232,11 +415,12 @@ fn TIMER_IRQ_0() {
         alarm.clear_interrupt();
         alarm.schedule(USB_SEND_INTERVAL).unwrap();
         alarm.enable_interrupt();
+        let counter = TIMER.borrow(cs).borrow().as_ref().unwrap().get_counter();
         if let Some(Err(e)) = KEYBOARD
             .borrow(cs)
-            .borrow()
-            .as_ref()
-            .map(Controller::send_keys)
+            .borrow_mut()
+            .as_mut()
+            .map(|keyboard| keyboard.send_keys(counter))
         {
             defmt::warn!("UsbError: {}", defmt::Debug2Format(&e));
         }
@@ -252,11 +436,11 @@ fn TIMER_IRQ_1() {
         let alarm = alarm.as_mut().unwrap();
         alarm.clear_interrupt();
 
+        let counter = TIMER.borrow(cs).borrow().as_ref().unwrap().get_counter();
         let mut keyboard = KEYBOARD.borrow(cs).borrow_mut();
         let keyboard = keyboard.as_mut().unwrap();
-        keyboard.main_loop();
+        keyboard.main_loop(counter);
 
-        let counter = TIMER.borrow(cs).borrow().as_ref().unwrap().get_counter();
         let mut last_counter = LAST_KEYS_ON.borrow(cs).borrow_mut();
         let should_sleep = (counter - *last_counter) >= SLEEP_MODE_INTERVAL;
 
@@ -280,3 +464,17 @@ fn TIMER_IRQ_1() {
         SLEEP_MODE.store(sleep_mode, Ordering::Relaxed);
     });
 }
+
+#[allow(non_snake_case)]
+#[interrupt]
+fn TIMER_IRQ_2() {
+    // KEYBOARDには触らないのでSpinlock0は取らない
+    critical_section::with(|cs| unsafe {
+        let mut alarm = ALARM2.borrow(cs).borrow_mut();
+        let alarm = alarm.as_mut().unwrap();
+        alarm.clear_interrupt();
+        let wait = SEQUENCER.borrow(cs).borrow_mut().as_mut().unwrap().step();
+        alarm.schedule(wait).unwrap();
+        alarm.enable_interrupt();
+    });
+}
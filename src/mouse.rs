@@ -0,0 +1,251 @@
+use fugit::MicrosDurationU32;
+use rp2040_hal::timer::Instant;
+
+use crate::{gamepad::Curve, usb::MouseReport};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseAction {
+    Up,
+    Down,
+    Left,
+    Right,
+    WheelUp,
+    WheelDown,
+    WheelLeft,
+    WheelRight,
+    /// 左クリック
+    Button1,
+    /// 右クリック
+    Button2,
+    /// 中クリック
+    Button3,
+}
+
+/// マウスキーの速さの設定
+pub struct MouseKeys {
+    /// これより浅い押し込み(0.0〜1.0)は一番遅い
+    pub dead_zone: f32,
+    /// これより深い押し込みは一番速い
+    pub saturation: f32,
+    /// 押し込み量から速さへの応答曲線
+    pub curve: Curve,
+    /// カーソルの1秒あたりのカウント(一番浅いとき、一番深いとき)
+    pub cursor_speed: (f32, f32),
+    /// ホイールの1秒あたりのノッチ(一番浅いとき、一番深いとき)
+    pub wheel_speed: (f32, f32),
+    /// 押し始めの速さ(押し続けたときの速さに対する割合)
+    pub initial_scale: f32,
+    /// 押し続けてから最後まで加速する時間
+    pub acceleration_time: MicrosDurationU32,
+    pub acceleration_curve: Curve,
+}
+
+impl MouseKeys {
+    fn speed(&self, (min, max): (f32, f32), depth: f32, scale: f32) -> f32 {
+        let value = ((depth - self.dead_zone) / (self.saturation - self.dead_zone)).clamp(0.0, 1.0);
+        (min + (max - min) * self.curve.apply(value)) * scale
+    }
+}
+
+/// マウスキーの状態
+///
+/// 動いた量はレポートを送るまで溜めておく(送れなかった分も次のレポートで送る)
+pub struct Mouse {
+    keys: MouseKeys,
+    // 動かすキーを押し始めた時刻
+    moving_since: Option<Instant>,
+    updated_at: Option<Instant>,
+    // 1カウントに満たない移動量(x, y, wheel, pan)
+    remainders: [f32; 4],
+    report: MouseReport,
+    sent_buttons: u8,
+}
+
+impl Mouse {
+    // スキャンが止まっていたときに飛ばないように
+    const MAX_INTERVAL: f32 = 0.05;
+
+    pub fn new(keys: MouseKeys) -> Mouse {
+        Mouse {
+            keys,
+            moving_since: None,
+            updated_at: None,
+            remainders: [0.0; 4],
+            report: MouseReport::default(),
+            sent_buttons: 0,
+        }
+    }
+
+    /// `actions`は押しているマウスキーとその押し込み量(スキャンごとに呼ぶ)
+    pub fn update(&mut self, actions: &[(MouseAction, f32)], now: Instant) {
+        let interval = match self.updated_at {
+            Some(updated_at) => {
+                ((now - updated_at).to_micros() as f32 / 1_000_000.0).min(Self::MAX_INTERVAL)
+            }
+            None => 0.0,
+        };
+        self.updated_at = Some(now);
+
+        let mut buttons = 0;
+        let mut moving = false;
+        for (action, _) in actions {
+            match action {
+                MouseAction::Button1 => buttons |= 0x01,
+                MouseAction::Button2 => buttons |= 0x02,
+                MouseAction::Button3 => buttons |= 0x04,
+                _ => moving = true,
+            }
+        }
+        self.report.buttons = buttons;
+        if !moving {
+            self.moving_since = None;
+            self.remainders = [0.0; 4];
+            return;
+        }
+
+        let moving_since = *self.moving_since.get_or_insert(now);
+        let elapsed = (now - moving_since).to_micros() as f32
+            / self.keys.acceleration_time.to_micros() as f32;
+        let initial = self.keys.initial_scale;
+        let scale =
+            initial + (1.0 - initial) * self.keys.acceleration_curve.apply(elapsed.min(1.0));
+
+        let mut velocities = [0.0_f32; 4];
+        for (action, depth) in actions {
+            let cursor = self.keys.speed(self.keys.cursor_speed, *depth, scale);
+            let wheel = self.keys.speed(self.keys.wheel_speed, *depth, scale);
+            match action {
+                MouseAction::Up => velocities[1] -= cursor,
+                MouseAction::Down => velocities[1] += cursor,
+                MouseAction::Left => velocities[0] -= cursor,
+                MouseAction::Right => velocities[0] += cursor,
+                MouseAction::WheelUp => velocities[2] += wheel,
+                MouseAction::WheelDown => velocities[2] -= wheel,
+                MouseAction::WheelLeft => velocities[3] -= wheel,
+                MouseAction::WheelRight => velocities[3] += wheel,
+                _ => {}
+            }
+        }
+
+        let mut counts = [0_i8; 4];
+        for ((count, remainder), velocity) in counts
+            .iter_mut()
+            .zip(self.remainders.iter_mut())
+            .zip(velocities)
+        {
+            *remainder += velocity * interval;
+            let whole = remainder.clamp(-127.0, 127.0) as i8;
+            *remainder -= whole as f32;
+            *count = whole;
+        }
+        self.report.add(counts[0], counts[1], counts[2], counts[3]);
+    }
+
+    /// 送るものがあればレポート
+    pub fn report(&self) -> Option<MouseReport> {
+        (self.report.is_moving() || self.report.buttons != self.sent_buttons).then_some(self.report)
+    }
+
+    /// レポートが読まれたら呼ぶ
+    pub fn sent(&mut self) {
+        self.sent_buttons = self.report.buttons;
+        self.report = MouseReport {
+            buttons: self.report.buttons,
+            ..MouseReport::default()
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 一番浅いと100カウント/秒、一番深いと1000カウント/秒。`initial_scale`倍から100msで加速しきる
+    fn mouse(initial_scale: f32) -> Mouse {
+        Mouse::new(MouseKeys {
+            dead_zone: 0.1,
+            saturation: 0.9,
+            curve: Curve::Linear,
+            cursor_speed: (100.0, 1000.0),
+            wheel_speed: (100.0, 1000.0),
+            initial_scale,
+            acceleration_time: MicrosDurationU32::millis(100),
+            acceleration_curve: Curve::Linear,
+        })
+    }
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_ticks(ms * 1_000)
+    }
+
+    #[test]
+    fn accumulates_movement_under_one_count() {
+        let mut mouse = mouse(1.0);
+        let right = [(MouseAction::Right, 0.0)];
+        mouse.update(&right, at(0));
+        // 5msで0.5カウント
+        mouse.update(&right, at(5));
+        assert_eq!(mouse.report(), None);
+        mouse.update(&right, at(10));
+        assert_eq!(mouse.report().map(|report| report.x), Some(1));
+    }
+
+    #[test]
+    fn speeds_up_while_held() {
+        let mut mouse = mouse(0.5);
+        let down = [(MouseAction::Down, 1.0)];
+        mouse.update(&down, at(0));
+        // 10ms後はまだ0.55倍
+        mouse.update(&down, at(10));
+        assert_eq!(mouse.report().map(|report| report.y), Some(5));
+        for ms in (20..=100).step_by(10) {
+            mouse.sent();
+            mouse.update(&down, at(ms));
+        }
+        assert_eq!(mouse.report().map(|report| report.y), Some(10));
+    }
+
+    #[test]
+    fn scrolls_the_wheel_and_pans() {
+        let mut mouse = mouse(1.0);
+        let actions = [(MouseAction::WheelUp, 1.0), (MouseAction::WheelLeft, 0.0)];
+        mouse.update(&actions, at(0));
+        mouse.update(&actions, at(10));
+        let report = mouse.report().unwrap();
+        assert_eq!(
+            (report.x, report.y, report.wheel, report.pan),
+            (0, 0, 10, -1)
+        );
+    }
+
+    #[test]
+    fn reports_button_changes_only() {
+        let mut mouse = mouse(1.0);
+        mouse.update(&[(MouseAction::Button1, 1.0)], at(0));
+        assert_eq!(
+            mouse.report(),
+            Some(MouseReport {
+                buttons: 0x01,
+                ..MouseReport::default()
+            })
+        );
+        mouse.sent();
+        mouse.update(&[(MouseAction::Button1, 1.0)], at(10));
+        assert_eq!(mouse.report(), None);
+        mouse.update(&[], at(20));
+        assert_eq!(mouse.report(), Some(MouseReport::default()));
+        mouse.sent();
+        assert_eq!(mouse.report(), None);
+    }
+
+    #[test]
+    fn keeps_unsent_movement() {
+        let mut mouse = mouse(1.0);
+        let left = [(MouseAction::Left, 1.0)];
+        mouse.update(&left, at(0));
+        mouse.update(&left, at(10));
+        // レポートが読まれなかった分も足して送る
+        mouse.update(&left, at(20));
+        assert_eq!(mouse.report().map(|report| report.x), Some(-20));
+    }
+}
@@ -0,0 +1,73 @@
+use crate::{layout::BaseLayout, unicode::UnicodeInput};
+
+// 保存した設定が読めなくならないよう、並びは変えずに末尾に足す
+const UNICODE_INPUTS: [UnicodeInput; 3] = [
+    UnicodeInput::MacOs,
+    UnicodeInput::Linux,
+    UnicodeInput::WinCompose,
+];
+const BASE_LAYOUTS: [BaseLayout; 3] = [BaseLayout::Qwerty, BaseLayout::Colemak, BaseLayout::Dvorak];
+
+/// Flashに保存する設定(項目は末尾に足す)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Settings {
+    pub gamepad: bool,
+    pub unicode_input: UnicodeInput,
+    pub base_layout: BaseLayout,
+}
+
+impl Settings {
+    /// 1項目1バイトで書き出す
+    pub fn write_bytes(&self, buf: &mut [u8]) -> usize {
+        buf[0] = self.gamepad as u8;
+        buf[1] = index_of(&UNICODE_INPUTS, self.unicode_input);
+        buf[2] = index_of(&BASE_LAYOUTS, self.base_layout);
+        3
+    }
+
+    /// 足りない項目や読めない項目はデフォルトにする
+    pub fn from_bytes(bytes: &[u8]) -> Option<Settings> {
+        let byte = |i: usize| bytes.get(i).copied();
+        let default = Settings::default();
+        Some(Settings {
+            gamepad: byte(0).map_or(default.gamepad, |b| b == 1),
+            unicode_input: byte(1)
+                .and_then(|b| UNICODE_INPUTS.get(b as usize).copied())
+                .unwrap_or(default.unicode_input),
+            base_layout: byte(2)
+                .and_then(|b| BASE_LAYOUTS.get(b as usize).copied())
+                .unwrap_or(default.base_layout),
+        })
+    }
+}
+
+fn index_of<T: PartialEq>(table: &[T], value: T) -> u8 {
+    table.iter().position(|v| *v == value).unwrap_or(0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_and_defaults_missing_items() {
+        let settings = Settings {
+            gamepad: true,
+            unicode_input: UnicodeInput::WinCompose,
+            base_layout: BaseLayout::Dvorak,
+        };
+        let mut buf = [0; 8];
+        let len = settings.write_bytes(&mut buf);
+        assert_eq!(Settings::from_bytes(&buf[..len]), Some(settings));
+
+        // 古い形式(項目が少ない)や知らない値
+        let read = Settings::from_bytes(&[1, 9]).unwrap();
+        assert_eq!(
+            read,
+            Settings {
+                gamepad: true,
+                ..Settings::default()
+            }
+        );
+    }
+}
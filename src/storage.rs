@@ -0,0 +1,189 @@
+use core::{
+    mem::size_of,
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use rp2040_hal::rom_data;
+
+use crate::{layout::Keymap, settings::Settings, switches::Calibration};
+
+const XIP_BASE: u32 = 0x1000_0000;
+const FLASH_SIZE: u32 = 2048 * 1024;
+const SECTOR_SIZE: usize = 4096;
+const PAGE_SIZE: usize = 256;
+// memory.xでFLASHの末尾16KiBを空けてある
+const SECTOR_COUNT: u32 = 4;
+const STORAGE_OFFSET: u32 = FLASH_SIZE - SECTOR_COUNT * SECTOR_SIZE as u32;
+
+static PARK_REQUESTED: AtomicBool = AtomicBool::new(false);
+static PARKED: AtomicBool = AtomicBool::new(false);
+
+/// Flashの1セクタに保存する値
+///
+/// メモリの中身をそのまま読むと古い値で壊れるので、バイト列にしてから書き込む
+pub trait Record: Sized {
+    const SECTOR: u32;
+    const MAGIC: u32;
+
+    /// `buf`に書き込んで、書いた長さを返す
+    fn encode(&self, buf: &mut [u8]) -> usize;
+
+    /// 読めなければNone
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+impl<const ROWS: usize, const COLS: usize> Record for Calibration<ROWS, COLS> {
+    const SECTOR: u32 = 0;
+    const MAGIC: u32 = u32::from_le_bytes(*b"CAL3");
+
+    fn encode(&self, buf: &mut [u8]) -> usize {
+        self.write_bytes(buf)
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Self::from_bytes(bytes)
+    }
+}
+
+impl Record for Keymap {
+    const SECTOR: u32 = 1;
+    const MAGIC: u32 = u32::from_le_bytes(*b"KMAP");
+
+    fn encode(&self, buf: &mut [u8]) -> usize {
+        self.write_bytes(buf)
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Self::from_bytes(bytes)
+    }
+}
+
+impl Record for Settings {
+    const SECTOR: u32 = 2;
+    const MAGIC: u32 = u32::from_le_bytes(*b"SET4");
+
+    fn encode(&self, buf: &mut [u8]) -> usize {
+        self.write_bytes(buf)
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Self::from_bytes(bytes)
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Header {
+    magic: u32,
+    len: u32,
+    checksum: u32,
+}
+
+pub fn load<T: Record>() -> Option<T> {
+    let base = (XIP_BASE + sector_offset::<T>()) as *const u8;
+    let header = unsafe { ptr::read_unaligned(base as *const Header) };
+    if header.magic != T::MAGIC || header.len as usize > SECTOR_SIZE - size_of::<Header>() {
+        return None;
+    }
+
+    let body =
+        unsafe { core::slice::from_raw_parts(base.add(size_of::<Header>()), header.len as usize) };
+    if checksum(body) != header.checksum {
+        return None;
+    }
+    T::decode(body)
+}
+
+/// core1を止めてから書き込むので、core0の割り込みの外から呼ぶこと
+pub fn save<T: Record>(record: &T) {
+    const {
+        assert!(T::SECTOR < SECTOR_COUNT);
+    };
+
+    let mut buf = [0xff_u8; SECTOR_SIZE];
+    let body_len = record.encode(&mut buf[size_of::<Header>()..]);
+    let len = size_of::<Header>() + body_len;
+    let header = Header {
+        magic: T::MAGIC,
+        len: body_len as u32,
+        checksum: checksum(&buf[size_of::<Header>()..len]),
+    };
+    unsafe {
+        ptr::write_unaligned(buf.as_mut_ptr() as *mut Header, header);
+    }
+
+    let rom = RomFunctions::new();
+    let offset = sector_offset::<T>();
+    let len = len.next_multiple_of(PAGE_SIZE);
+
+    PARK_REQUESTED.store(true, Ordering::Release);
+    while !PARKED.load(Ordering::Acquire) {
+        core::hint::spin_loop()
+    }
+    critical_section::with(|_| unsafe { write_sector(&rom, offset, buf.as_ptr(), len) });
+    PARK_REQUESTED.store(false, Ordering::Release);
+}
+
+/// 書き込み中はXIPが使えないので、core1はループの先頭でこれを呼んでRAM上で待つ
+pub fn park_core1_if_requested() {
+    if PARK_REQUESTED.load(Ordering::Acquire) {
+        unsafe { park() }
+    }
+}
+
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn park() {
+    PARKED.store(true, Ordering::Release);
+    while PARK_REQUESTED.load(Ordering::Acquire) {
+        core::hint::spin_loop()
+    }
+    PARKED.store(false, Ordering::Release);
+}
+
+// ROM関数のポインタはXIPを止める前に引いておく
+struct RomFunctions {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+    flash_enter_cmd_xip: unsafe extern "C" fn(),
+}
+
+impl RomFunctions {
+    fn new() -> Self {
+        RomFunctions {
+            connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+            flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+            flash_range_erase: rom_data::flash_range_erase::ptr(),
+            flash_range_program: rom_data::flash_range_program::ptr(),
+            flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+            flash_enter_cmd_xip: rom_data::flash_enter_cmd_xip::ptr(),
+        }
+    }
+}
+
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn write_sector(rom: &RomFunctions, offset: u32, data: *const u8, len: usize) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    // 4KiBのセクタ消去(0x20)
+    (rom.flash_range_erase)(offset, SECTOR_SIZE, SECTOR_SIZE as u32, 0x20);
+    (rom.flash_range_program)(offset, data, len);
+    (rom.flash_flush_cache)();
+    (rom.flash_enter_cmd_xip)();
+}
+
+fn sector_offset<T: Record>() -> u32 {
+    STORAGE_OFFSET + T::SECTOR * SECTOR_SIZE as u32
+}
+
+// FNV-1a
+fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |hash, b| {
+        (hash ^ *b as u32).wrapping_mul(0x0100_0193)
+    })
+}
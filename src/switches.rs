@@ -1,7 +1,30 @@
-mod buffer;
+mod actuation;
+mod adaptive_kalman_filter;
+mod analog_sampler;
+mod baseline_tracker;
+mod calibration;
+mod diagnostics;
+mod ema_filter;
+mod filter;
+mod frame_sampler;
 mod kalman_filter;
 mod key_matrix;
+mod median_filter;
+mod rapid_trigger;
+mod scan_sequencer;
 mod switch_identifier;
 
+pub use actuation::Actuation;
+pub use adaptive_kalman_filter::AdaptiveKalmanFilter;
+pub use analog_sampler::AnalogSampler;
+pub use calibration::Calibration;
+pub use diagnostics::Health;
+pub use ema_filter::EmaFilter;
+pub use filter::{AnyFilter, Filter};
+pub use frame_sampler::FrameSampler;
+pub use kalman_filter::KalmanFilter;
 pub use key_matrix::KeyMatrix;
+pub use median_filter::MedianFilter;
+pub use rapid_trigger::RapidTrigger;
+pub use scan_sequencer::{Frame, ScanSequencer, SharedFrame};
 pub use switch_identifier::SwitchIdentifier;
@@ -0,0 +1,28 @@
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Thresholds {
+    pub press: f32,
+    pub release: f32,
+}
+
+/// 押下・解放を判定する深さ(無負荷時を0.0、底打ちを1.0とする)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Actuation {
+    pub press: f32,
+    pub release: f32,
+}
+
+impl Actuation {
+    pub const DEFAULT: Actuation = Actuation {
+        press: 0.5,
+        release: 0.35,
+    };
+
+    pub fn thresholds(&self, baseline: f32, peak: f32) -> Thresholds {
+        let travel = peak - baseline;
+        Thresholds {
+            press: baseline + travel * self.press,
+            // releaseがpressより深いとチャタリングするので揃える
+            release: baseline + travel * self.release.min(self.press),
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use super::filter::Filter;
+
+/// 観測ノイズの分散をイノベーションから推定しながら動くカルマンフィルタ
+#[derive(Debug, Clone)]
+pub struct AdaptiveKalmanFilter {
+    state: Option<(f32, f32)>,
+    process_sigma: f32,
+    noise_sigma: f32,
+    min_noise_sigma: f32,
+    // 推定の更新の速さ
+    beta: f32,
+}
+
+impl AdaptiveKalmanFilter {
+    // イノベーションが標準偏差のこの倍より大きければ信号の変化とみなす
+    const GATE: f32 = 3.0;
+
+    pub fn new(process_sigma: f32, min_noise_sigma: f32, beta: f32) -> AdaptiveKalmanFilter {
+        AdaptiveKalmanFilter {
+            state: None,
+            process_sigma,
+            noise_sigma: min_noise_sigma,
+            min_noise_sigma,
+            beta,
+        }
+    }
+}
+
+impl Filter for AdaptiveKalmanFilter {
+    fn predict(&mut self, observation: f32) -> f32 {
+        let Some((mu, sigma)) = self.state else {
+            self.state = Some((observation, self.noise_sigma));
+            return observation;
+        };
+
+        let prior_sigma = sigma + self.process_sigma;
+        let innovation = observation - mu;
+        // E[innovation^2] = prior_sigma + noise_sigma
+        // キーを押したときのような大きな変化はノイズの推定に入れない
+        let expected = prior_sigma + self.noise_sigma;
+        if innovation * innovation <= Self::GATE * Self::GATE * expected {
+            let estimated = innovation * innovation - prior_sigma;
+            self.noise_sigma = ((1.0 - self.beta) * self.noise_sigma + self.beta * estimated)
+                .max(self.min_noise_sigma);
+        }
+
+        let gain = prior_sigma / (prior_sigma + self.noise_sigma);
+        let mu = mu + gain * innovation;
+        self.state = Some((mu, (1.0 - gain) * prior_sigma));
+        mu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise_after(observations: impl Iterator<Item = f32>) -> f32 {
+        let mut filter = AdaptiveKalmanFilter::new(1.0, 1.0, 0.1);
+        for observation in observations {
+            filter.predict(observation);
+        }
+        filter.noise_sigma
+    }
+
+    #[test]
+    fn estimates_larger_noise_for_noisier_input() {
+        let quiet = noise_after((0..100).map(|i| if i % 2 == 0 { 49.0 } else { 51.0 }));
+        let noisy = noise_after((0..100).map(|i| if i % 2 == 0 { 47.0 } else { 53.0 }));
+        assert!(quiet < noisy);
+        assert!(noisy > 2.0);
+    }
+
+    #[test]
+    fn tracks_a_sudden_step() {
+        let mut filter = AdaptiveKalmanFilter::new(1.0, 1.0, 0.1);
+        assert_eq!(filter.predict(0.0), 0.0);
+        let values = [0; 3].map(|_| filter.predict(100.0));
+        assert!(values[0] > 50.0);
+        assert!(values[2] > 90.0);
+        // 押したことでノイズが大きいと推定しない
+        assert!(filter.noise_sigma < 2.0);
+    }
+}
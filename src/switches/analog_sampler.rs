@@ -0,0 +1,19 @@
+/// 指定したキーのセンサーの生の値を読む
+///
+/// `KeyMatrix`はこれを通してしか値を読まないので、記録した値や合成した値を流し込めば
+/// ハードウェアなしでもスキャン・フィルタ・閾値判定を動かせる
+pub trait AnalogSampler {
+    /// 1回のスキャンの前に呼ばれる
+    fn begin(&mut self) {}
+
+    /// スキャン中は列ごとに、各列の中では行ごとに順番に呼ばれる
+    fn sample(&mut self, row: usize, col: usize) -> u16;
+
+    /// 1回のスキャンの後に呼ばれる
+    fn end(&mut self) {}
+
+    /// 基板の温度(℃)。測れなければNone
+    fn temperature(&self) -> Option<f32> {
+        None
+    }
+}
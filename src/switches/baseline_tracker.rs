@@ -0,0 +1,142 @@
+use super::actuation::Thresholds;
+
+/// 離しているキーの無負荷時の値のずれを少しずつ追いかける
+///
+/// ずれはキャリブレーション時の値からの差で持ち、センサーの値から引いてから押下判定する
+pub struct BaselineTracker<const ROWS: usize, const COLS: usize> {
+    offsets: [[f32; COLS]; ROWS],
+    temperature_coefficient: Option<f32>,
+    reference_temperature: Option<f32>,
+    temperature_offset: f32,
+}
+
+impl<const ROWS: usize, const COLS: usize> BaselineTracker<ROWS, COLS> {
+    // 5ms周期で時定数が10秒くらい
+    const ALPHA: f32 = 0.0005;
+    // ゆっくり押し込んだときに追いかけすぎないように
+    const MAX_OFFSET: f32 = 20.0;
+
+    pub fn new() -> Self {
+        BaselineTracker {
+            offsets: [[0.0; COLS]; ROWS],
+            temperature_coefficient: None,
+            reference_temperature: None,
+            temperature_offset: 0.0,
+        }
+    }
+
+    /// センサーの値から引く量
+    pub fn correction(&self, row: usize, col: usize) -> f32 {
+        self.offsets[row][col] + self.temperature_offset
+    }
+
+    /// 1℃あたりの値の変化。Noneなら温度では補正しない
+    pub fn set_temperature_coefficient(&mut self, coefficient: Option<f32>) {
+        self.temperature_coefficient = coefficient;
+    }
+
+    /// キャリブレーションし直したらずれは0に戻す
+    ///
+    /// `temperature`はキャリブレーションしたときの温度(なければ最初に測った温度を基準にする)
+    pub fn reset(&mut self, temperature: Option<f32>) {
+        self.offsets = [[0.0; COLS]; ROWS];
+        self.reference_temperature = temperature;
+        self.temperature_offset = 0.0;
+    }
+
+    /// スキャンの最初に呼ぶ
+    pub fn set_temperature(&mut self, temperature: Option<f32>) {
+        let Some(temperature) = temperature else {
+            return;
+        };
+        let reference = *self.reference_temperature.get_or_insert(temperature);
+        self.temperature_offset = self
+            .temperature_coefficient
+            .map_or(0.0, |coefficient| coefficient * (temperature - reference));
+    }
+
+    /// `value`は補正後の値
+    pub fn update(
+        &mut self,
+        row: usize,
+        col: usize,
+        value: f32,
+        baseline: f32,
+        thresholds: &Thresholds,
+        pressed: bool,
+    ) {
+        // 離していて、離す位置までの半分より浅いときだけ追いかける
+        if pressed || value > (baseline + thresholds.release) / 2.0 {
+            return;
+        }
+        let offset = &mut self.offsets[row][col];
+        *offset =
+            (*offset + Self::ALPHA * (value - baseline)).clamp(-Self::MAX_OFFSET, Self::MAX_OFFSET);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASELINE: f32 = 100.0;
+    // 離す位置は240、追いかけるのは170まで
+    const THRESHOLDS: Thresholds = Thresholds {
+        press: 300.0,
+        release: 240.0,
+    };
+
+    // `raw`を読み続けたときの補正量
+    fn correction_after(scans: usize, raw: f32, pressed: bool) -> f32 {
+        let mut tracker = BaselineTracker::<1, 1>::new();
+        for _ in 0..scans {
+            let value = raw - tracker.correction(0, 0);
+            tracker.update(0, 0, value, BASELINE, &THRESHOLDS, pressed);
+        }
+        tracker.correction(0, 0)
+    }
+
+    #[test]
+    fn tracks_a_slow_drift() {
+        // 時定数(2000スキャン)では6割ほど
+        let partly = correction_after(2_000, 110.0, false);
+        assert!((6.0..7.0).contains(&partly));
+        assert!((correction_after(20_000, 110.0, false) - 10.0).abs() < 0.01);
+        assert!((correction_after(20_000, 95.0, false) + 5.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn clamps_the_offset() {
+        assert_eq!(correction_after(20_000, 160.0, false), 20.0);
+        assert_eq!(correction_after(20_000, 0.0, false), -20.0);
+    }
+
+    #[test]
+    fn does_not_absorb_held_keys() {
+        assert_eq!(correction_after(20_000, 400.0, true), 0.0);
+        assert_eq!(correction_after(20_000, 150.0, true), 0.0);
+        // 押下と判定されていなくても、浅く押しているときは追いかけない
+        assert_eq!(correction_after(20_000, 200.0, false), 0.0);
+    }
+
+    #[test]
+    fn corrects_by_temperature() {
+        let mut tracker = BaselineTracker::<1, 1>::new();
+        tracker.set_temperature(Some(30.0));
+        assert_eq!(tracker.correction(0, 0), 0.0);
+
+        tracker.set_temperature_coefficient(Some(2.0));
+        tracker.reset(Some(25.0));
+        tracker.set_temperature(Some(30.0));
+        assert_eq!(tracker.correction(0, 0), 10.0);
+        // 測れなかったときはそのまま
+        tracker.set_temperature(None);
+        assert_eq!(tracker.correction(0, 0), 10.0);
+
+        // キャリブレーションの温度がなければ最初に測った温度が基準
+        tracker.reset(None);
+        tracker.set_temperature(Some(30.0));
+        tracker.set_temperature(Some(28.0));
+        assert_eq!(tracker.correction(0, 0), -4.0);
+    }
+}
@@ -0,0 +1,162 @@
+use super::actuation::{Actuation, Thresholds};
+
+/// キーごとの無負荷時の値と底打ち時の値
+#[derive(Debug, Clone, Copy)]
+pub struct Calibration<const ROWS: usize, const COLS: usize> {
+    pub baseline: [[u16; COLS]; ROWS],
+    pub peak: [[u16; COLS]; ROWS],
+    /// キャリブレーションしたときの温度(℃)
+    pub temperature: Option<f32>,
+}
+
+impl<const ROWS: usize, const COLS: usize> Calibration<ROWS, COLS> {
+    /// これより変化の小さいキーはキャリブレーションできていないものとする
+    pub const MIN_TRAVEL: u16 = 30;
+
+    pub fn is_calibrated(&self, row: usize, col: usize) -> bool {
+        self.peak[row][col] >= self.baseline[row][col] + Self::MIN_TRAVEL
+    }
+
+    pub fn thresholds(&self, row: usize, col: usize, actuation: &Actuation) -> Option<Thresholds> {
+        if !self.is_calibrated(row, col) {
+            return None;
+        }
+
+        Some(actuation.thresholds(self.baseline[row][col] as f32, self.peak[row][col] as f32))
+    }
+
+    /// 無負荷時の値、底打ち時の値(行優先のu16 LE)と温度(なければNaN)を書き出す
+    pub fn write_bytes(&self, buf: &mut [u8]) -> usize {
+        let values = self.baseline.iter().chain(&self.peak).flatten();
+        let mut len = 0;
+        for value in values {
+            buf[len..len + 2].copy_from_slice(&value.to_le_bytes());
+            len += 2;
+        }
+        let temperature = self.temperature.unwrap_or(f32::NAN);
+        buf[len..len + 4].copy_from_slice(&temperature.to_le_bytes());
+        len + 4
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != ROWS * COLS * 4 + 4 {
+            return None;
+        }
+        let value = |i: usize| u16::from_le_bytes([bytes[i * 2], bytes[i * 2 + 1]]);
+        let mut calibration = Calibration {
+            baseline: [[0; COLS]; ROWS],
+            peak: [[0; COLS]; ROWS],
+            temperature: None,
+        };
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                calibration.baseline[row][col] = value(row * COLS + col);
+                calibration.peak[row][col] = value((ROWS + row) * COLS + col);
+            }
+        }
+        let temperature = f32::from_le_bytes(bytes[bytes.len() - 4..].try_into().ok()?);
+        calibration.temperature = (!temperature.is_nan()).then_some(temperature);
+        Some(calibration)
+    }
+}
+
+pub struct Calibrator<const ROWS: usize, const COLS: usize> {
+    scans: u32,
+    sums: [[u32; COLS]; ROWS],
+    calibration: Calibration<ROWS, COLS>,
+    all_released: bool,
+}
+
+impl<const ROWS: usize, const COLS: usize> Calibrator<ROWS, COLS> {
+    // 5ms周期で1秒間は触らずに待ってもらい、その間の平均を無負荷時の値とする
+    const BASELINE_SCANS: u32 = 200;
+    const TIMEOUT_SCANS: u32 = 12_000;
+
+    pub fn new() -> Self {
+        Calibrator {
+            scans: 0,
+            sums: [[0; COLS]; ROWS],
+            calibration: Calibration {
+                baseline: [[0; COLS]; ROWS],
+                peak: [[0; COLS]; ROWS],
+                temperature: None,
+            },
+            all_released: true,
+        }
+    }
+
+    pub fn update(&mut self, row: usize, col: usize, value: u16) {
+        if self.scans < Self::BASELINE_SCANS {
+            self.sums[row][col] += value as u32;
+            return;
+        }
+
+        let baseline = self.calibration.baseline[row][col];
+        let peak = &mut self.calibration.peak[row][col];
+        *peak = (*peak).max(value);
+        if value >= baseline + Calibration::<ROWS, COLS>::MIN_TRAVEL / 2 {
+            self.all_released = false;
+        }
+    }
+
+    /// 全部のキーを一度ずつ底まで押して離したら(もしくは時間切れで)完了
+    pub fn finish_scan(&mut self) -> Option<Calibration<ROWS, COLS>> {
+        self.scans += 1;
+        if self.scans == Self::BASELINE_SCANS {
+            for (row, sums) in self.sums.iter().enumerate() {
+                for (col, sum) in sums.iter().enumerate() {
+                    let baseline = (sum / Self::BASELINE_SCANS) as u16;
+                    self.calibration.baseline[row][col] = baseline;
+                    self.calibration.peak[row][col] = baseline;
+                }
+            }
+            defmt::info!("Calibration: baseline recorded, press every key to the bottom");
+            return None;
+        }
+
+        let all_released = core::mem::replace(&mut self.all_released, true);
+        let all_pressed =
+            (0..ROWS).all(|row| (0..COLS).all(|col| self.calibration.is_calibrated(row, col)));
+        if (self.scans > Self::BASELINE_SCANS && all_pressed && all_released)
+            || self.scans >= Self::TIMEOUT_SCANS
+        {
+            Some(self.calibration)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let mut calibration = Calibration::<2, 3> {
+            baseline: [[1, 2, 3], [4, 5, 6]],
+            peak: [[100, 200, 300], [400, 500, 65535]],
+            temperature: Some(27.5),
+        };
+        let mut buf = [0; 64];
+        let len = calibration.write_bytes(&mut buf);
+        assert_eq!(len, 2 * 2 * 6 + 4);
+        let read = Calibration::<2, 3>::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(
+            (read.baseline, read.peak),
+            (calibration.baseline, calibration.peak)
+        );
+        assert_eq!(read.temperature, Some(27.5));
+
+        calibration.temperature = None;
+        let len = calibration.write_bytes(&mut buf);
+        assert_eq!(
+            Calibration::<2, 3>::from_bytes(&buf[..len])
+                .unwrap()
+                .temperature,
+            None
+        );
+        // 大きさの違うマトリクスのものは読まない
+        assert!(Calibration::<3, 3>::from_bytes(&buf[..len]).is_none());
+    }
+}
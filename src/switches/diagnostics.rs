@@ -0,0 +1,205 @@
+use super::calibration::Calibration;
+
+// キーごとの統計(判定期間ごとにリセットする)
+// mean/varianceは離している間の生の値から求める(押している間の変化をノイズとみなさないため)
+#[derive(Debug, Clone, Copy)]
+struct KeyStatistics {
+    samples: u32,
+    mean: f32,
+    m2: f32,
+    min: u16,
+    max: u16,
+    // 閾値を超えていたスキャン回数
+    pressed_scans: u32,
+    presses: u32,
+}
+
+impl KeyStatistics {
+    const EMPTY: KeyStatistics = KeyStatistics {
+        samples: 0,
+        mean: 0.0,
+        m2: 0.0,
+        min: u16::MAX,
+        max: 0,
+        pressed_scans: 0,
+        presses: 0,
+    };
+
+    fn variance(&self) -> f32 {
+        if self.samples == 0 {
+            0.0
+        } else {
+            self.m2 / self.samples as f32
+        }
+    }
+
+    // Welford
+    fn add_released(&mut self, value: u16) {
+        self.samples += 1;
+        let delta = value as f32 - self.mean;
+        self.mean += delta / self.samples as f32;
+        self.m2 += delta * (value as f32 - self.mean);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct Health {
+    /// 押されたままになっている
+    pub stuck: bool,
+    /// センサーから値が来ていない
+    pub open: bool,
+    /// ノイズが大きい、もしくはチャタリングしている
+    pub noisy: bool,
+}
+
+impl Health {
+    pub fn is_ok(&self) -> bool {
+        !(self.stuck || self.open || self.noisy)
+    }
+}
+
+pub struct Diagnostics<const ROWS: usize, const COLS: usize> {
+    scans: u32,
+    statistics: [[KeyStatistics; COLS]; ROWS],
+    held_scans: [[u32; COLS]; ROWS],
+    // 値がずっとこれ以下なら断線とみなす(キャリブレーションするまでは判定しない)
+    open_max: [[Option<u16>; COLS]; ROWS],
+    health: [[Health; COLS]; ROWS],
+}
+
+impl<const ROWS: usize, const COLS: usize> Diagnostics<ROWS, COLS> {
+    // 5ms周期で10秒ごとに判定する
+    const WINDOW_SCANS: u32 = 2_000;
+    // 30秒押しっぱなしなら固着とみなす
+    const STUCK_SCANS: u32 = 6_000;
+    const NOISY_SIGMA: f32 = 4.0;
+    // 10秒で200回(20Hz)は人の打鍵ではない
+    const CHATTER_PRESSES: u32 = 200;
+
+    pub fn new() -> Self {
+        Diagnostics {
+            scans: 0,
+            statistics: [[KeyStatistics::EMPTY; COLS]; ROWS],
+            held_scans: [[0; COLS]; ROWS],
+            open_max: [[None; COLS]; ROWS],
+            health: [[Health::default(); COLS]; ROWS],
+        }
+    }
+
+    /// 正常なキーは離していても無負荷時の値が出るので、その半分に届かなければ断線とみなす
+    pub fn set_calibration(&mut self, calibration: &Calibration<ROWS, COLS>) {
+        // 断線したキーはキャリブレーションできないので、できたキーで一番低い値を使う
+        let lowest = (0..ROWS)
+            .flat_map(|row| (0..COLS).map(move |col| (row, col)))
+            .filter(|&(row, col)| calibration.is_calibrated(row, col))
+            .map(|(row, col)| calibration.baseline[row][col])
+            .min();
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                let baseline = if calibration.is_calibrated(row, col) {
+                    Some(calibration.baseline[row][col])
+                } else {
+                    lowest
+                };
+                self.open_max[row][col] = baseline.map(|baseline| baseline / 2);
+            }
+        }
+    }
+
+    pub fn health(&self) -> [[Health; COLS]; ROWS] {
+        self.health
+    }
+
+    /// `raw`はフィルタ前の値
+    pub fn update(&mut self, row: usize, col: usize, raw: u16, pressed: bool) {
+        let statistics = &mut self.statistics[row][col];
+        statistics.min = statistics.min.min(raw);
+        statistics.max = statistics.max.max(raw);
+
+        let held_scans = &mut self.held_scans[row][col];
+        if pressed {
+            if *held_scans == 0 {
+                statistics.presses += 1;
+            }
+            *held_scans = held_scans.saturating_add(1);
+            statistics.pressed_scans += 1;
+        } else {
+            *held_scans = 0;
+            statistics.add_released(raw);
+        }
+    }
+
+    pub fn finish_scan(&mut self) {
+        self.scans += 1;
+        if self.scans < Self::WINDOW_SCANS {
+            return;
+        }
+        self.scans = 0;
+
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                let statistics = &self.statistics[row][col];
+                let health = Health {
+                    stuck: self.held_scans[row][col] >= Self::STUCK_SCANS,
+                    open: self.open_max[row][col]
+                        .is_some_and(|open_max| statistics.max <= open_max),
+                    noisy: statistics.variance() > Self::NOISY_SIGMA * Self::NOISY_SIGMA
+                        || statistics.presses > Self::CHATTER_PRESSES,
+                };
+                if health != self.health[row][col] {
+                    if health.is_ok() {
+                        defmt::info!("Switch ({}, {}) recovered", row, col);
+                    } else {
+                        defmt::warn!(
+                            "Switch ({}, {}): {} mean={} variance={} min={} max={} pressed={}",
+                            row,
+                            col,
+                            health,
+                            statistics.mean,
+                            statistics.variance(),
+                            statistics.min,
+                            statistics.max,
+                            statistics.pressed_scans
+                        );
+                    }
+                }
+                self.health[row][col] = health;
+            }
+        }
+        self.statistics = [[KeyStatistics::EMPTY; COLS]; ROWS];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_window(diagnostics: &mut Diagnostics<1, 2>, values: [u16; 2]) {
+        for _ in 0..Diagnostics::<1, 2>::WINDOW_SCANS {
+            for (col, value) in values.iter().enumerate() {
+                diagnostics.update(0, col, *value, false);
+            }
+            diagnostics.finish_scan();
+        }
+    }
+
+    #[test]
+    fn open_threshold_follows_calibration() {
+        let mut diagnostics = Diagnostics::<1, 2>::new();
+        // キャリブレーションするまでは断線を判定しない
+        run_window(&mut diagnostics, [0, 0]);
+        assert!(diagnostics.health().iter().flatten().all(Health::is_ok));
+
+        // 2つ目のキーはキャリブレーションできなかった
+        diagnostics.set_calibration(&Calibration {
+            baseline: [[40, 0]],
+            peak: [[400, 0]],
+            temperature: None,
+        });
+        run_window(&mut diagnostics, [21, 21]);
+        assert!(diagnostics.health().iter().flatten().all(Health::is_ok));
+        run_window(&mut diagnostics, [20, 3]);
+        assert!(diagnostics.health()[0][0].open);
+        assert!(diagnostics.health()[0][1].open);
+    }
+}
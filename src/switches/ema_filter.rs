@@ -0,0 +1,44 @@
+use super::filter::Filter;
+
+/// 指数移動平均。alphaが大きいほど追従が速い
+#[derive(Debug, Clone)]
+pub struct EmaFilter {
+    state: Option<f32>,
+    alpha: f32,
+}
+
+impl EmaFilter {
+    pub fn new(alpha: f32) -> EmaFilter {
+        EmaFilter { state: None, alpha }
+    }
+}
+
+impl Filter for EmaFilter {
+    fn predict(&mut self, observation: f32) -> f32 {
+        let state = match self.state {
+            Some(state) => state + self.alpha * (observation - state),
+            None => observation,
+        };
+        self.state = Some(state);
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_at_first_observation() {
+        let mut filter = EmaFilter::new(0.5);
+        let outputs = [40.0, 0.0, 0.0, 8.0].map(|x| filter.predict(x));
+        assert_eq!(outputs, [40.0, 20.0, 10.0, 9.0]);
+    }
+
+    #[test]
+    fn alpha_one_passes_through() {
+        let mut filter = EmaFilter::new(1.0);
+        let outputs = [3.0, 7.0, 1.0].map(|x| filter.predict(x));
+        assert_eq!(outputs, [3.0, 7.0, 1.0]);
+    }
+}
@@ -0,0 +1,46 @@
+use super::{
+    adaptive_kalman_filter::AdaptiveKalmanFilter, ema_filter::EmaFilter,
+    kalman_filter::KalmanFilter, median_filter::MedianFilter,
+};
+
+pub trait Filter {
+    fn predict(&mut self, observation: f32) -> f32;
+}
+
+/// キーごとに違う種類のフィルタを使うとき用
+#[derive(Debug, Clone)]
+pub enum AnyFilter {
+    Kalman(KalmanFilter),
+    AdaptiveKalman(AdaptiveKalmanFilter),
+    Ema(EmaFilter),
+    Median(MedianFilter<5>),
+}
+
+impl Filter for AnyFilter {
+    fn predict(&mut self, observation: f32) -> f32 {
+        match self {
+            AnyFilter::Kalman(f) => f.predict(observation),
+            AnyFilter::AdaptiveKalman(f) => f.predict(observation),
+            AnyFilter::Ema(f) => f.predict(observation),
+            AnyFilter::Median(f) => f.predict(observation),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dispatches_to_the_selected_filter() {
+        let mut any = AnyFilter::Ema(EmaFilter::new(0.5));
+        let mut ema = EmaFilter::new(0.5);
+        for x in [10.0, 30.0, 20.0] {
+            assert_eq!(any.predict(x), ema.predict(x));
+        }
+
+        let mut any = AnyFilter::Median(MedianFilter::new());
+        let outputs = [1.0, 1.0, 9.0, 1.0].map(|x| any.predict(x));
+        assert_eq!(outputs, [1.0, 1.0, 1.0, 1.0]);
+    }
+}
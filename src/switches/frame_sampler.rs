@@ -0,0 +1,33 @@
+use super::{
+    analog_sampler::AnalogSampler,
+    scan_sequencer::{Frame, SharedFrame},
+};
+
+/// `ScanSequencer`が読み終えた最新のフレームから値を返す
+pub struct FrameSampler<const ROWS: usize, const COLS: usize> {
+    shared: &'static SharedFrame<ROWS, COLS>,
+    frame: Frame<ROWS, COLS>,
+}
+
+impl<const ROWS: usize, const COLS: usize> FrameSampler<ROWS, COLS> {
+    pub fn new(shared: &'static SharedFrame<ROWS, COLS>) -> FrameSampler<ROWS, COLS> {
+        FrameSampler {
+            shared,
+            frame: Frame::EMPTY,
+        }
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize> AnalogSampler for FrameSampler<ROWS, COLS> {
+    fn begin(&mut self) {
+        self.frame = critical_section::with(|cs| self.shared.borrow(cs).get());
+    }
+
+    fn sample(&mut self, row: usize, col: usize) -> u16 {
+        self.frame.values[row][col]
+    }
+
+    fn temperature(&self) -> Option<f32> {
+        self.frame.temperature
+    }
+}
@@ -1,3 +1,5 @@
+use super::filter::Filter;
+
 #[derive(Debug, Clone)]
 pub struct KalmanFilter {
     state: Option<Gaussian>,
@@ -13,8 +15,10 @@ impl KalmanFilter {
             noise_sigma,
         }
     }
+}
 
-    pub fn predict(&mut self, observation: f32) -> f32 {
+impl Filter for KalmanFilter {
+    fn predict(&mut self, observation: f32) -> f32 {
         if let Some(ref mut state) = self.state {
             let prior = Gaussian::new(state.mu, state.sigma + self.noise_sigma);
             let gain = prior.sigma / (prior.sigma + self.state_sigma);
@@ -41,3 +45,31 @@ impl Gaussian {
         Self { mu, sigma }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converges_to_a_step() {
+        let mut filter = KalmanFilter::new(2.0, 10.0);
+        assert_eq!(filter.predict(0.0), 0.0);
+        let mut previous = 0.0;
+        for _ in 0..20 {
+            let value = filter.predict(100.0);
+            assert!(previous <= value && value <= 100.0);
+            previous = value;
+        }
+        assert!(previous > 99.0);
+    }
+
+    #[test]
+    fn smooths_alternating_noise() {
+        let mut filter = KalmanFilter::new(2.0, 1.0);
+        for i in 0..100 {
+            filter.predict(if i % 2 == 0 { 45.0 } else { 55.0 });
+        }
+        let value = filter.predict(45.0);
+        assert!((value - 50.0).abs() < 5.0);
+    }
+}
@@ -1,144 +1,354 @@
 use core::mem::{transmute_copy, MaybeUninit};
 
-use embedded_hal::{adc::OneShot as _, blocking::delay::DelayUs, digital::v2::OutputPin as _};
-use rp2040_hal::{
-    adc::{Adc, AdcPin},
-    gpio::{bank0::Gpio26, DynPinId, FunctionNull, FunctionSioOutput, Pin, PullDown},
-};
 use rustkbd::{keyboard::KeySwitches, Vec};
 
-use super::{buffer::Buffer, kalman_filter::KalmanFilter, switch_identifier::SwitchIdentifier};
-
-pub struct KeyMatrix<D: DelayUs<u16>, const ROWS: usize, const CSELS: usize, const COLS: usize> {
-    rows: [Pin<DynPinId, FunctionSioOutput, PullDown>; ROWS],
-    mux_selectors: [Pin<DynPinId, FunctionSioOutput, PullDown>; CSELS],
-    mux_enabled: Pin<DynPinId, FunctionSioOutput, PullDown>,
-    opa_shutdown: Pin<DynPinId, FunctionSioOutput, PullDown>,
-    rst_charge: Pin<DynPinId, FunctionSioOutput, PullDown>,
-    adc: Adc,
-    adc_pin: AdcPin<Pin<Gpio26, FunctionNull, PullDown>>,
-    delay: D,
-    filters: [[KalmanFilter; COLS]; ROWS],
-    buffers: [[Buffer<3>; COLS]; ROWS],
+use super::{
+    actuation::{Actuation, Thresholds},
+    analog_sampler::AnalogSampler,
+    baseline_tracker::BaselineTracker,
+    calibration::{Calibration, Calibrator},
+    diagnostics::{Diagnostics, Health},
+    filter::Filter,
+    rapid_trigger::RapidTrigger,
+    switch_identifier::SwitchIdentifier,
+};
+
+pub struct KeyMatrix<S: AnalogSampler, F: Filter, const ROWS: usize, const COLS: usize> {
+    sampler: S,
+    filters: [[F; COLS]; ROWS],
+    raw_values: [[u16; COLS]; ROWS],
     values: [[u16; COLS]; ROWS],
+    actuations: [[Actuation; COLS]; ROWS],
+    thresholds: [[Thresholds; COLS]; ROWS],
+    pressed: [[bool; COLS]; ROWS],
+    extremes: [[f32; COLS]; ROWS],
+    rapid_trigger: Option<RapidTrigger>,
+    calibration: Option<Calibration<ROWS, COLS>>,
+    calibrator: Option<Calibrator<ROWS, COLS>>,
+    calibration_finished: bool,
+    diagnostics: Diagnostics<ROWS, COLS>,
+    baseline_tracker: BaselineTracker<ROWS, COLS>,
 }
 
-impl<D: DelayUs<u16>, const ROWS: usize, const CSELS: usize, const COLS: usize>
-    KeyMatrix<D, ROWS, CSELS, COLS>
+impl<S: AnalogSampler, F: Filter, const ROWS: usize, const COLS: usize>
+    KeyMatrix<S, F, ROWS, COLS>
 {
-    pub const THRESHOLD: f32 = 40.0;
-
-    #[allow(clippy::too_many_arguments)]
-    pub fn new(
-        rows: [Pin<DynPinId, FunctionSioOutput, PullDown>; ROWS],
-        mux_selectors: [Pin<DynPinId, FunctionSioOutput, PullDown>; CSELS],
-        mut mux_enabled: Pin<DynPinId, FunctionSioOutput, PullDown>,
-        mut opa_shutdown: Pin<DynPinId, FunctionSioOutput, PullDown>,
-        mut rst_charge: Pin<DynPinId, FunctionSioOutput, PullDown>,
-        adc: Adc,
-        adc_pin: AdcPin<Pin<Gpio26, FunctionNull, PullDown>>,
-        delay: D,
-    ) -> KeyMatrix<D, ROWS, CSELS, COLS> {
-        mux_enabled.set_high().ok();
-        opa_shutdown.set_low().ok();
-        rst_charge.set_high().ok();
-
-        let mut filters: [[MaybeUninit<KalmanFilter>; COLS]; ROWS] =
-            unsafe { MaybeUninit::uninit().assume_init() };
-        for slot in filters.iter_mut() {
-            for slot in slot.iter_mut() {
-                *slot = MaybeUninit::new(KalmanFilter::new(2.0, 10.0));
-            }
-        }
+    // キャリブレーションしていないキーは0からこの値までをストロークとみなす
+    // (Actuation::DEFAULTで押下判定が40になる)
+    pub const NOMINAL_PEAK: f32 = 80.0;
 
-        let mut buffers: [[MaybeUninit<Buffer<3>>; COLS]; ROWS] =
+    /// `filter`はキーごとに呼ばれる
+    pub fn new(sampler: S, filter: impl Fn(SwitchIdentifier) -> F) -> KeyMatrix<S, F, ROWS, COLS> {
+        let mut filters: [[MaybeUninit<F>; COLS]; ROWS] =
             unsafe { MaybeUninit::uninit().assume_init() };
-        for slot in buffers.iter_mut() {
-            for slot in slot.iter_mut() {
-                *slot = MaybeUninit::new(Buffer::new());
+        for (row, slot) in filters.iter_mut().enumerate() {
+            for (col, slot) in slot.iter_mut().enumerate() {
+                *slot = MaybeUninit::new(filter(SwitchIdentifier {
+                    row: row as u8,
+                    col: col as u8,
+                }));
             }
         }
 
         KeyMatrix {
-            rows,
-            mux_selectors,
-            mux_enabled,
-            opa_shutdown,
-            rst_charge,
-            adc,
-            adc_pin,
-            delay,
-            filters: unsafe { transmute_copy::<_, [[KalmanFilter; COLS]; ROWS]>(&filters) },
-            buffers: unsafe { transmute_copy::<_, [[Buffer<3>; COLS]; ROWS]>(&buffers) },
+            sampler,
+            filters: unsafe { transmute_copy::<_, [[F; COLS]; ROWS]>(&filters) },
+            raw_values: [[0; COLS]; ROWS],
             values: [[0; COLS]; ROWS],
+            actuations: [[Actuation::DEFAULT; COLS]; ROWS],
+            thresholds: [[Actuation::DEFAULT.thresholds(0.0, Self::NOMINAL_PEAK); COLS]; ROWS],
+            pressed: [[false; COLS]; ROWS],
+            extremes: [[0.0; COLS]; ROWS],
+            rapid_trigger: None,
+            calibration: None,
+            calibrator: None,
+            calibration_finished: false,
+            diagnostics: Diagnostics::new(),
+            baseline_tracker: BaselineTracker::new(),
         }
     }
 
+    /// フィルタ前の値
+    pub fn raw_values(&self) -> [[u16; COLS]; ROWS] {
+        self.raw_values
+    }
+
     pub fn values(&self) -> [[u16; COLS]; ROWS] {
         self.values
     }
 
+    /// キャリブレーションの範囲を0.0〜1.0とした押し込み量
+    pub fn depths(&self) -> [[f32; COLS]; ROWS] {
+        let mut depths = [[0.0; COLS]; ROWS];
+        for (row, depths) in depths.iter_mut().enumerate() {
+            for (col, depth) in depths.iter_mut().enumerate() {
+                let (baseline, peak) = self
+                    .calibrated_stroke(row, col)
+                    .unwrap_or((0.0, Self::NOMINAL_PEAK));
+                let value =
+                    self.values[row][col] as f32 - self.baseline_tracker.correction(row, col);
+                *depth = ((value - baseline) / (peak - baseline)).clamp(0.0, 1.0);
+            }
+        }
+        depths
+    }
+
+    fn calibrated_stroke(&self, row: usize, col: usize) -> Option<(f32, f32)> {
+        let calibration = self.calibration.as_ref()?;
+        calibration.is_calibrated(row, col).then(|| {
+            (
+                calibration.baseline[row][col] as f32,
+                calibration.peak[row][col] as f32,
+            )
+        })
+    }
+
+    /// ずれの補正を戻した、センサーの値で見た押下判定の閾値
+    pub fn press_thresholds(&self) -> [[u16; COLS]; ROWS] {
+        let mut thresholds = [[0; COLS]; ROWS];
+        for (row, thresholds) in thresholds.iter_mut().enumerate() {
+            for (col, threshold) in thresholds.iter_mut().enumerate() {
+                *threshold = (self.thresholds[row][col].press
+                    + self.baseline_tracker.correction(row, col))
+                    as u16;
+            }
+        }
+        thresholds
+    }
+
+    pub fn health(&self) -> [[Health; COLS]; ROWS] {
+        self.diagnostics.health()
+    }
+
     pub fn is_any_key_pressed(&self) -> bool {
-        self.values
-            .map(|row| row.iter().any(|v| *v as f32 > Self::THRESHOLD))
-            .iter()
-            .any(|r| *r)
+        self.pressed.iter().any(|row| row.iter().any(|p| *p))
+    }
+
+    pub fn set_calibration(&mut self, calibration: Calibration<ROWS, COLS>) {
+        self.calibration = Some(calibration);
+        self.baseline_tracker.reset(calibration.temperature);
+        self.diagnostics.set_calibration(&calibration);
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                self.update_thresholds(row, col);
+            }
+        }
+    }
+
+    pub fn set_actuation(&mut self, switch: SwitchIdentifier, actuation: Actuation) {
+        let (row, col) = (switch.row as usize, switch.col as usize);
+        self.actuations[row][col] = actuation;
+        self.update_thresholds(row, col);
+    }
+
+    fn update_thresholds(&mut self, row: usize, col: usize) {
+        let actuation = &self.actuations[row][col];
+        self.thresholds[row][col] = self
+            .calibration
+            .and_then(|calibration| calibration.thresholds(row, col, actuation))
+            .unwrap_or_else(|| actuation.thresholds(0.0, Self::NOMINAL_PEAK));
+    }
+
+    /// 1℃あたりのセンサーの値の変化(離しているキーのずれの追従とは別に足す)
+    pub fn set_temperature_coefficient(&mut self, coefficient: Option<f32>) {
+        self.baseline_tracker
+            .set_temperature_coefficient(coefficient);
+    }
+
+    pub fn set_rapid_trigger(&mut self, rapid_trigger: Option<RapidTrigger>) {
+        self.rapid_trigger = rapid_trigger;
+        self.extremes = self.values.map(|row| row.map(f32::from));
+    }
+
+    /// 全キーを離した状態で呼ぶこと
+    pub fn start_calibration(&mut self) {
+        defmt::info!("Calibration started, keep all keys released");
+        self.calibrator = Some(Calibrator::new());
+        self.pressed = [[false; COLS]; ROWS];
+    }
+
+    /// 完了したキャリブレーションを一度だけ返す(Flashへの保存用)
+    pub fn take_finished_calibration(&mut self) -> Option<Calibration<ROWS, COLS>> {
+        if core::mem::take(&mut self.calibration_finished) {
+            self.calibration
+        } else {
+            None
+        }
     }
 }
 
-impl<D: DelayUs<u16>, const ROWS: usize, const CSELS: usize, const COLS: usize> KeySwitches<2, 12>
-    for KeyMatrix<D, ROWS, CSELS, COLS>
+impl<S: AnalogSampler, F: Filter, const ROWS: usize, const COLS: usize> KeySwitches<2, 12>
+    for KeyMatrix<S, F, ROWS, COLS>
 {
     type Identifier = SwitchIdentifier;
 
     fn scan(&mut self) -> Vec<Self::Identifier, 12> {
         let mut keys = Vec::<Self::Identifier, 12>::new();
 
-        // opa_shutdownとmux_enabledは実際はHi/Loが逆
-        self.opa_shutdown.set_high().ok();
-        self.mux_enabled.set_low().ok();
-
+        self.sampler.begin();
+        self.baseline_tracker
+            .set_temperature(self.sampler.temperature());
         for col in 0..COLS {
-            // マルチプレクサの設定
-            self.mux_enabled.set_high().ok();
-            for sel in 0..CSELS {
-                self.mux_selectors[sel]
-                    .set_state((col & (1 << sel) != 0).into())
-                    .ok();
-            }
-            self.mux_enabled.set_low().ok();
-            self.delay.delay_us(10);
-
             for row in 0..ROWS {
-                self.rst_charge.set_low().ok();
-                self.delay.delay_us(40);
-                self.rows[row].set_high().unwrap();
-                self.delay.delay_us(8);
-
-                let val: u16 = self.adc.read(&mut self.adc_pin).unwrap_or(0);
-                self.delay.delay_us(8);
+                let raw = self.sampler.sample(row, col);
                 // if col == 0 && row == 0 {
-                // defmt::debug!("{}", val);
+                // defmt::debug!("{}", raw);
                 // }
-                let val = self.filters[row][col].predict(val.into());
+                self.raw_values[row][col] = raw;
+                let val = self.filters[row][col].predict(raw.into());
                 self.values[row][col] = val as u16;
-                if self.buffers[row][col].update(val > Self::THRESHOLD) {
+                if let Some(calibrator) = self.calibrator.as_mut() {
+                    calibrator.update(row, col, val as u16);
+                }
+
+                // 押下判定は無負荷時の値のずれを引いてから行う
+                let val = val - self.baseline_tracker.correction(row, col);
+                let thresholds = self.thresholds[row][col];
+                self.pressed[row][col] = if let Some(rapid_trigger) = self.rapid_trigger {
+                    rapid_trigger.update(
+                        self.pressed[row][col],
+                        &mut self.extremes[row][col],
+                        val,
+                        &thresholds,
+                    )
+                } else if self.pressed[row][col] {
+                    val > thresholds.release
+                } else {
+                    val > thresholds.press
+                };
+                self.diagnostics
+                    .update(row, col, raw, self.pressed[row][col]);
+                // キャリブレーションしていないキーは基準がないので追いかけない
+                if let (None, Some((baseline, _))) =
+                    (self.calibrator.as_ref(), self.calibrated_stroke(row, col))
+                {
+                    self.baseline_tracker.update(
+                        row,
+                        col,
+                        val,
+                        baseline,
+                        &thresholds,
+                        self.pressed[row][col],
+                    );
+                }
+                // キャリブレーション中はキーを送らない
+                if self.pressed[row][col] && self.calibrator.is_none() {
                     let key_identifier = SwitchIdentifier {
                         row: row as u8,
                         col: col as u8,
                     };
                     keys.push(key_identifier).ok();
                 }
-
-                self.rows[row].set_low().unwrap();
-                self.rst_charge.set_high().ok();
-                self.delay.delay_us(5);
             }
         }
+        self.sampler.end();
+        self.diagnostics.finish_scan();
 
-        self.mux_enabled.set_high().ok();
-        self.opa_shutdown.set_low().ok();
+        if let Some(mut calibration) = self.calibrator.as_mut().and_then(Calibrator::finish_scan) {
+            calibration.temperature = self.sampler.temperature();
+            defmt::info!("Calibration finished");
+            self.calibrator = None;
+            self.calibration_finished = true;
+            self.set_calibration(calibration);
+        }
 
         keys
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{vec, vec::Vec};
+
+    use super::*;
+
+    type Frame = [[u16; 2]; 1];
+
+    /// 決まった値の列を1スキャンずつ返す
+    struct TraceSampler {
+        trace: Vec<Frame>,
+        scans: usize,
+    }
+
+    impl AnalogSampler for TraceSampler {
+        fn sample(&mut self, row: usize, col: usize) -> u16 {
+            self.trace[self.scans][row][col]
+        }
+
+        fn end(&mut self) {
+            self.scans += 1;
+        }
+    }
+
+    struct Passthrough;
+
+    impl Filter for Passthrough {
+        fn predict(&mut self, observation: f32) -> f32 {
+            observation
+        }
+    }
+
+    fn key_matrix(trace: Vec<Frame>) -> KeyMatrix<TraceSampler, Passthrough, 1, 2> {
+        KeyMatrix::new(TraceSampler { trace, scans: 0 }, |_| Passthrough)
+    }
+
+    /// トレースを最後までスキャンして、各スキャンで押されていたキーの列を返す
+    fn pressed_cols(matrix: &mut KeyMatrix<TraceSampler, Passthrough, 1, 2>) -> Vec<Vec<u8>> {
+        let scans = matrix.sampler.trace.len() - matrix.sampler.scans;
+        (0..scans)
+            .map(|_| matrix.scan().iter().map(|switch| switch.col).collect())
+            .collect()
+    }
+
+    #[test]
+    fn fixed_thresholds_without_calibration() {
+        // 押下は40、解放は28
+        let mut matrix = key_matrix(vec![[[0, 20]], [[0, 50]], [[0, 60]], [[0, 30]], [[0, 20]]]);
+        assert_eq!(
+            pressed_cols(&mut matrix),
+            vec![vec![], vec![1], vec![1], vec![1], vec![]]
+        );
+        assert_eq!(matrix.raw_values(), [[0, 20]]);
+    }
+
+    #[test]
+    fn rapid_trigger_follows_direction() {
+        let mut matrix = key_matrix(vec![
+            [[50, 0]],
+            [[60, 0]],
+            [[52, 0]],
+            [[49, 0]],
+            [[58, 0]],
+            [[59, 0]],
+        ]);
+        matrix.set_rapid_trigger(Some(RapidTrigger {
+            press_delta: 10.0,
+            release_delta: 10.0,
+        }));
+        assert_eq!(
+            pressed_cols(&mut matrix),
+            vec![vec![0], vec![0], vec![0], vec![], vec![], vec![0]]
+        );
+    }
+
+    #[test]
+    fn calibration_from_trace_sets_thresholds() {
+        // 無負荷時を記録してから、1キーずつ底まで押して離す
+        let mut trace = vec![[[100, 200]]; 200];
+        trace.extend([[[400, 200]], [[100, 200]], [[100, 600]], [[100, 200]]]);
+        trace.extend([[[100, 200]], [[240, 200]], [[260, 420]]]);
+        let mut matrix = key_matrix(trace);
+        matrix.start_calibration();
+        for _ in 0..204 {
+            assert!(matrix.scan().is_empty());
+        }
+
+        let calibration = matrix.take_finished_calibration().unwrap();
+        assert_eq!(calibration.baseline, [[100, 200]]);
+        assert_eq!(calibration.peak, [[400, 600]]);
+        assert!(matrix.take_finished_calibration().is_none());
+        // 押下判定は無負荷時から底打ちまでの半分
+        assert_eq!(matrix.press_thresholds(), [[250, 400]]);
+        assert_eq!(pressed_cols(&mut matrix), vec![vec![], vec![], vec![0, 1]]);
+    }
+}
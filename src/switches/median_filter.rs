@@ -0,0 +1,57 @@
+use super::filter::Filter;
+
+/// 直近N回の中央値。スパイク状のノイズに強いが(N-1)/2回分遅れる
+#[derive(Debug, Clone)]
+pub struct MedianFilter<const N: usize> {
+    buf: [f32; N],
+    len: usize,
+    pos: usize,
+}
+
+impl<const N: usize> MedianFilter<N> {
+    pub fn new() -> Self {
+        MedianFilter {
+            buf: [0.0; N],
+            len: 0,
+            pos: 0,
+        }
+    }
+}
+
+impl<const N: usize> Default for MedianFilter<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Filter for MedianFilter<N> {
+    fn predict(&mut self, observation: f32) -> f32 {
+        self.buf[self.pos] = observation;
+        self.pos = (self.pos + 1) % N;
+        self.len = (self.len + 1).min(N);
+
+        let mut sorted = self.buf;
+        let sorted = &mut sorted[..self.len];
+        sorted.sort_unstable_by(f32::total_cmp);
+        sorted[self.len / 2]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_single_spikes() {
+        let mut filter = MedianFilter::<3>::new();
+        let outputs = [10.0, 10.0, 100.0, 10.0, 12.0].map(|x| filter.predict(x));
+        assert_eq!(outputs, [10.0, 10.0, 10.0, 10.0, 12.0]);
+    }
+
+    #[test]
+    fn follows_steps_with_delay() {
+        let mut filter = MedianFilter::<3>::new();
+        let outputs = [0.0, 0.0, 0.0, 50.0, 50.0, 50.0].map(|x| filter.predict(x));
+        assert_eq!(outputs, [0.0, 0.0, 0.0, 0.0, 50.0, 50.0]);
+    }
+}
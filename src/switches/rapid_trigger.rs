@@ -0,0 +1,39 @@
+use super::actuation::Thresholds;
+
+/// 押し込み位置によらず、戻り始めたら離し、押し込み始めたら押す
+#[derive(Debug, Clone, Copy)]
+pub struct RapidTrigger {
+    pub press_delta: f32,
+    pub release_delta: f32,
+}
+
+impl RapidTrigger {
+    /// `extreme`は押されている間は最深値、離されている間は最浅値
+    pub fn update(
+        &self,
+        pressed: bool,
+        extreme: &mut f32,
+        value: f32,
+        thresholds: &Thresholds,
+    ) -> bool {
+        // release以下まで戻っていれば常に離す
+        if value <= thresholds.release {
+            *extreme = value;
+            return false;
+        }
+
+        let next = if pressed {
+            value > *extreme - self.release_delta
+        } else {
+            value >= *extreme + self.press_delta
+        };
+        if next != pressed {
+            *extreme = value;
+        } else if pressed {
+            *extreme = extreme.max(value);
+        } else {
+            *extreme = extreme.min(value);
+        }
+        next
+    }
+}
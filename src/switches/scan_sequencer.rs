@@ -0,0 +1,248 @@
+use core::cell::Cell;
+
+use critical_section::Mutex;
+use embedded_hal::adc::OneShot as _;
+use embedded_hal::digital::v2::OutputPin as _;
+use fugit::MicrosDurationU32;
+use rp2040_hal::{
+    adc::{Adc, AdcFifo, AdcPin, DmaReadTarget, TempSense},
+    dma::{single_buffer, SingleChannel},
+    gpio::{bank0::Gpio26, DynPinId, FunctionNull, FunctionSioOutput, Pin, PullDown},
+};
+
+/// 1フレーム分のADCの値
+#[derive(Debug, Clone, Copy)]
+pub struct Frame<const ROWS: usize, const COLS: usize> {
+    pub values: [[u16; COLS]; ROWS],
+    /// RP2040の内蔵センサーで測った温度(℃)。まだ読んでいなければNone
+    pub temperature: Option<f32>,
+}
+
+impl<const ROWS: usize, const COLS: usize> Frame<ROWS, COLS> {
+    pub const EMPTY: Self = Frame {
+        values: [[0; COLS]; ROWS],
+        temperature: None,
+    };
+}
+
+/// 最新のフレーム
+pub type SharedFrame<const ROWS: usize, const COLS: usize> = Mutex<Cell<Frame<ROWS, COLS>>>;
+
+#[derive(Clone, Copy)]
+enum Step {
+    Begin,
+    SelectColumn(usize),
+    Discharge(usize, usize),
+    Sample(usize, usize),
+    Reset(usize, usize),
+    End,
+}
+
+enum Dma<CH: SingleChannel> {
+    Idle(CH, &'static mut [u16]),
+    Running(single_buffer::Transfer<CH, DmaReadTarget<u16>, &'static mut [u16]>),
+}
+
+/// 静電容量センサーの読み取りを細かいステップに分けて、タイマー割り込みから進める
+///
+/// 変換結果はADCのFIFOからDMAでバッファに転送し、1フレーム読み終わるごとに`frame`へ書き出す
+/// (ときどき内蔵の温度センサーも読んで一緒に書き出す)
+pub struct ScanSequencer<
+    CH: SingleChannel,
+    const ROWS: usize,
+    const CSELS: usize,
+    const COLS: usize,
+> {
+    rows: [Pin<DynPinId, FunctionSioOutput, PullDown>; ROWS],
+    mux_selectors: [Pin<DynPinId, FunctionSioOutput, PullDown>; CSELS],
+    mux_enabled: Pin<DynPinId, FunctionSioOutput, PullDown>,
+    opa_shutdown: Pin<DynPinId, FunctionSioOutput, PullDown>,
+    rst_charge: Pin<DynPinId, FunctionSioOutput, PullDown>,
+    // 温度を読む間だけNone
+    fifo: Option<AdcFifo<'static, u16>>,
+    adc_pin: AdcPin<Pin<Gpio26, FunctionNull, PullDown>>,
+    temp_sensor: TempSense,
+    dma: Option<Dma<CH>>,
+    step: Step,
+    cycles_per_us: u32,
+    frames: u32,
+    temperature: Option<f32>,
+    frame: &'static SharedFrame<ROWS, COLS>,
+}
+
+impl<CH: SingleChannel, const ROWS: usize, const CSELS: usize, const COLS: usize>
+    ScanSequencer<CH, ROWS, CSELS, COLS>
+{
+    // 1フレーム読み終えてから次のフレームを始めるまでの間隔
+    const FRAME_INTERVAL: u32 = 500;
+    // 温度はゆっくりしか変わらないので、このフレーム数ごとに読む
+    const TEMPERATURE_INTERVAL: u32 = 256;
+
+    /// `buffer`の長さは`ROWS * COLS`
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        rows: [Pin<DynPinId, FunctionSioOutput, PullDown>; ROWS],
+        mux_selectors: [Pin<DynPinId, FunctionSioOutput, PullDown>; CSELS],
+        mut mux_enabled: Pin<DynPinId, FunctionSioOutput, PullDown>,
+        mut opa_shutdown: Pin<DynPinId, FunctionSioOutput, PullDown>,
+        mut rst_charge: Pin<DynPinId, FunctionSioOutput, PullDown>,
+        adc: &'static mut Adc,
+        mut adc_pin: AdcPin<Pin<Gpio26, FunctionNull, PullDown>>,
+        dma_channel: CH,
+        buffer: &'static mut [u16],
+        cycles_per_us: u32,
+        frame: &'static SharedFrame<ROWS, COLS>,
+    ) -> ScanSequencer<CH, ROWS, CSELS, COLS> {
+        assert_eq!(buffer.len(), ROWS * COLS);
+        mux_enabled.set_high().ok();
+        opa_shutdown.set_low().ok();
+        rst_charge.set_high().ok();
+        let temp_sensor = adc.take_temp_sensor().unwrap();
+        let fifo = Self::start_fifo(adc, &mut adc_pin);
+
+        ScanSequencer {
+            rows,
+            mux_selectors,
+            mux_enabled,
+            opa_shutdown,
+            rst_charge,
+            fifo: Some(fifo),
+            adc_pin,
+            temp_sensor,
+            dma: Some(Dma::Idle(dma_channel, buffer)),
+            step: Step::Begin,
+            cycles_per_us,
+            frames: 0,
+            temperature: None,
+            frame,
+        }
+    }
+
+    /// 1ステップ進めて、次のステップまでの待ち時間を返す
+    pub fn step(&mut self) -> MicrosDurationU32 {
+        let (next, wait) = match self.step {
+            Step::Begin => {
+                self.start_transfer();
+                // opa_shutdownとmux_enabledは実際はHi/Loが逆
+                self.opa_shutdown.set_high().ok();
+                self.mux_enabled.set_low().ok();
+                (Step::SelectColumn(0), 0)
+            }
+            Step::SelectColumn(col) => {
+                // マルチプレクサの設定
+                self.mux_enabled.set_high().ok();
+                for sel in 0..CSELS {
+                    self.mux_selectors[sel]
+                        .set_state((col & (1 << sel) != 0).into())
+                        .ok();
+                }
+                self.mux_enabled.set_low().ok();
+                (Step::Discharge(0, col), 10)
+            }
+            Step::Discharge(row, col) => {
+                self.rst_charge.set_low().ok();
+                (Step::Sample(row, col), 40)
+            }
+            Step::Sample(row, col) => {
+                // 充電からサンプリングまでの時間は値に直接効くので、割り込みに任せずここで待つ
+                self.rows[row].set_high().ok();
+                cortex_m::asm::delay(8 * self.cycles_per_us);
+                if let Some(fifo) = self.fifo.as_mut() {
+                    fifo.trigger();
+                }
+                (Step::Reset(row, col), 8)
+            }
+            Step::Reset(row, col) => {
+                self.rows[row].set_low().ok();
+                self.rst_charge.set_high().ok();
+                let next = if row + 1 < ROWS {
+                    Step::Discharge(row + 1, col)
+                } else if col + 1 < COLS {
+                    Step::SelectColumn(col + 1)
+                } else {
+                    Step::End
+                };
+                (next, 5)
+            }
+            Step::End => {
+                self.mux_enabled.set_high().ok();
+                self.opa_shutdown.set_low().ok();
+                let values = self.finish_transfer();
+                if self.frames == 0 {
+                    self.read_temperature();
+                }
+                self.frames = (self.frames + 1) % Self::TEMPERATURE_INTERVAL;
+                if let Some(values) = values {
+                    let frame = Frame {
+                        values,
+                        temperature: self.temperature,
+                    };
+                    critical_section::with(|cs| self.frame.borrow(cs).set(frame));
+                }
+                (Step::Begin, Self::FRAME_INTERVAL)
+            }
+        };
+        self.step = next;
+        MicrosDurationU32::micros(wait)
+    }
+
+    fn start_fifo(
+        adc: &'static mut Adc,
+        adc_pin: &mut AdcPin<Pin<Gpio26, FunctionNull, PullDown>>,
+    ) -> AdcFifo<'static, u16> {
+        // 変換はtrigger()で1回ずつ行う
+        adc.build_fifo()
+            .set_channel(adc_pin)
+            .enable_dma()
+            .start_paused()
+    }
+
+    // FIFOはセンサーのチャンネルに固定されているので、一度止めて温度センサーを直接読む
+    fn read_temperature(&mut self) {
+        let Some(fifo) = self.fifo.take() else {
+            return;
+        };
+        let adc = fifo.stop();
+        let raw: Option<u16> = adc.read(&mut self.temp_sensor).ok();
+        self.fifo = Some(Self::start_fifo(adc, &mut self.adc_pin));
+
+        // データシートの式(27℃で0.706V、-1.721mV/℃)
+        if let Some(raw) = raw {
+            let voltage = raw as f32 * 3.3 / 4096.0;
+            self.temperature = Some(27.0 - (voltage - 0.706) / 0.001721);
+        }
+    }
+
+    fn start_transfer(&mut self) {
+        let Some(fifo) = self.fifo.as_mut() else {
+            return;
+        };
+        if let Some(Dma::Idle(channel, buffer)) = self.dma.take() {
+            fifo.clear();
+            let transfer =
+                single_buffer::Config::new(channel, fifo.dma_read_target(), buffer).start();
+            self.dma = Some(Dma::Running(transfer));
+        }
+    }
+
+    fn finish_transfer(&mut self) -> Option<[[u16; COLS]; ROWS]> {
+        let Some(Dma::Running(transfer)) = self.dma.take() else {
+            return None;
+        };
+        let (channel, _, buffer) = if transfer.is_done() {
+            transfer.wait()
+        } else {
+            defmt::warn!("ADC transfer did not complete");
+            transfer.abort()
+        };
+
+        // 列ごとに読んでいるので列優先で並んでいる
+        let mut values = [[0; COLS]; ROWS];
+        for (i, value) in buffer.iter().enumerate() {
+            // 最上位ビットはエラーフラグ
+            values[i % ROWS][i / ROWS] = value & 0x0fff;
+        }
+        self.dma = Some(Dma::Idle(channel, buffer));
+        Some(values)
+    }
+}
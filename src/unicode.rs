@@ -0,0 +1,117 @@
+use rustkbd::{keyboard::Key, Vec};
+
+use crate::macros::{MacroStep, DIGITS, LETTERS};
+
+/// ホストでUnicodeの文字を打つ方法(OSごとに違うので設定で選ぶ)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnicodeInput {
+    /// macOSの「Unicode 16進数入力」。Optionを押したまま16進数を打つ
+    #[default]
+    MacOs,
+    /// IBusなど。Ctrl+Shift+Uのあと16進数を打ってSpaceで確定する
+    Linux,
+    /// WindowsのWinCompose。Compose(右Alt)、uのあと16進数を打ってEnterで確定する
+    WinCompose,
+}
+
+impl UnicodeInput {
+    pub fn next(self) -> UnicodeInput {
+        match self {
+            UnicodeInput::MacOs => UnicodeInput::Linux,
+            UnicodeInput::Linux => UnicodeInput::WinCompose,
+            UnicodeInput::WinCompose => UnicodeInput::MacOs,
+        }
+    }
+
+    /// `c`を打つマクロ
+    pub fn steps(self, c: char) -> Vec<MacroStep, 24> {
+        let mut steps = Vec::new();
+        match self {
+            UnicodeInput::MacOs => {
+                // BMPの外はサロゲートペアで打つ
+                let mut units = [0; 2];
+                steps.push(MacroStep::Press(Key::LeftAlt)).ok();
+                for unit in c.encode_utf16(&mut units) {
+                    push_hex(&mut steps, *unit as u32);
+                }
+                steps.push(MacroStep::Release(Key::LeftAlt)).ok();
+            }
+            UnicodeInput::Linux => {
+                steps.push(MacroStep::Press(Key::LeftControl)).ok();
+                steps.push(MacroStep::Press(Key::LeftShift)).ok();
+                steps.push(MacroStep::Tap(Key::U)).ok();
+                steps.push(MacroStep::Release(Key::LeftShift)).ok();
+                steps.push(MacroStep::Release(Key::LeftControl)).ok();
+                push_hex(&mut steps, c as u32);
+                steps.push(MacroStep::Tap(Key::Space)).ok();
+            }
+            UnicodeInput::WinCompose => {
+                steps.push(MacroStep::Tap(Key::RightAlt)).ok();
+                steps.push(MacroStep::Tap(Key::U)).ok();
+                push_hex(&mut steps, c as u32);
+                steps.push(MacroStep::Tap(Key::Enter)).ok();
+            }
+        }
+        steps
+    }
+}
+
+// 最低4桁の16進数
+fn push_hex(steps: &mut Vec<MacroStep, 24>, value: u32) {
+    let digits = ((32 - value.leading_zeros()).div_ceil(4)).max(4);
+    for i in (0..digits).rev() {
+        let digit = ((value >> (i * 4)) & 0xf) as usize;
+        let key = if digit < 10 {
+            DIGITS[digit]
+        } else {
+            LETTERS[digit - 10]
+        };
+        steps.push(MacroStep::Tap(key)).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(value: u32) -> Vec<MacroStep, 24> {
+        let mut steps = Vec::new();
+        push_hex(&mut steps, value);
+        steps
+    }
+
+    fn taps(keys: &[Key]) -> Vec<MacroStep, 24> {
+        keys.iter().map(|key| MacroStep::Tap(*key)).collect()
+    }
+
+    #[test]
+    fn pads_hex_to_four_digits() {
+        assert_eq!(
+            hex(0x41),
+            taps(&[Key::Digit0, Key::Digit0, Key::Digit4, Key::Digit1])
+        );
+        assert_eq!(
+            hex(0),
+            taps(&[Key::Digit0, Key::Digit0, Key::Digit0, Key::Digit0])
+        );
+    }
+
+    #[test]
+    fn types_hex_letters_and_long_values() {
+        assert_eq!(hex(0xabcd), taps(&[Key::A, Key::B, Key::C, Key::D]));
+        assert_eq!(
+            hex(0x1f600),
+            taps(&[Key::Digit1, Key::F, Key::Digit6, Key::Digit0, Key::Digit0])
+        );
+    }
+
+    #[test]
+    fn uses_surrogate_pairs_on_macos() {
+        let steps = UnicodeInput::MacOs.steps('\u{1f600}');
+        assert_eq!(steps.first(), Some(&MacroStep::Press(Key::LeftAlt)));
+        assert_eq!(&steps[1..5], &hex(0xd83d)[..]);
+        assert_eq!(&steps[5..9], &hex(0xde00)[..]);
+        assert_eq!(steps.last(), Some(&MacroStep::Release(Key::LeftAlt)));
+        assert_eq!(steps.len(), 10);
+    }
+}
@@ -0,0 +1,11 @@
+mod communicator;
+mod hid_class;
+mod key_usage;
+mod keymap_class;
+mod reports;
+mod sensor_stream;
+
+pub use communicator::{DeviceInfo, UsbCommunicator};
+pub use hid_class::HidClass;
+pub use keymap_class::KEYMAP_REPORT_SIZE;
+pub use reports::{GamepadReport, MouseReport};
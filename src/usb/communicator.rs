@@ -0,0 +1,145 @@
+use rustkbd::keyboard::Key;
+use usb_device::{
+    class_prelude::*,
+    device::{StringDescriptors, UsbDevice, UsbDeviceBuilder, UsbVidPid},
+};
+
+use super::{
+    hid_class::HidClass,
+    keymap_class::{KeymapClass, KEYMAP_REPORT_SIZE},
+    reports::{
+        self, GamepadReport, MouseReport, CONSUMER_REPORT_DESCRIPTOR, GAMEPAD_REPORT_DESCRIPTOR,
+        KEYBOARD_REPORT_DESCRIPTOR, MOUSE_REPORT_DESCRIPTOR,
+    },
+    sensor_stream::SensorStreamClass,
+};
+
+pub struct DeviceInfo {
+    pub manufacturer: &'static str,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub product_name: &'static str,
+    pub serial_number: &'static str,
+}
+
+/// キーボードとメディアキーとマウス、センサーの値とキーマップの読み書き(とゲームパッド)の複合デバイス
+pub struct UsbCommunicator<'a, B: UsbBus> {
+    device: UsbDevice<'a, B>,
+    keyboard: HidClass<'a, B>,
+    consumer: HidClass<'a, B>,
+    mouse: HidClass<'a, B>,
+    sensor_stream: SensorStreamClass<'a, B>,
+    keymap: KeymapClass<'a, B>,
+    gamepad: Option<HidClass<'a, B>>,
+}
+
+impl<'a, B: UsbBus> UsbCommunicator<'a, B> {
+    /// ゲームパッドは列挙し直さないと追加できないので起動時に決める
+    pub fn new(
+        device_info: DeviceInfo,
+        bus: &'a UsbBusAllocator<B>,
+        gamepad: bool,
+    ) -> UsbCommunicator<'a, B> {
+        let keyboard = HidClass::new(bus, &KEYBOARD_REPORT_DESCRIPTOR, 8, 1);
+        let consumer = HidClass::new(bus, &CONSUMER_REPORT_DESCRIPTOR, 8, 0);
+        let mouse = HidClass::new(bus, &MOUSE_REPORT_DESCRIPTOR, 8, 0);
+        let sensor_stream = SensorStreamClass::new(bus);
+        let keymap = KeymapClass::new(bus);
+        let gamepad = gamepad.then(|| HidClass::new(bus, &GAMEPAD_REPORT_DESCRIPTOR, 8, 0));
+        let device = UsbDeviceBuilder::new(
+            bus,
+            UsbVidPid(device_info.vendor_id, device_info.product_id),
+        )
+        .strings(&[StringDescriptors::default()
+            .manufacturer(device_info.manufacturer)
+            .product(device_info.product_name)
+            .serial_number(device_info.serial_number)])
+        .unwrap()
+        .build();
+
+        UsbCommunicator {
+            device,
+            keyboard,
+            consumer,
+            mouse,
+            sensor_stream,
+            keymap,
+            gamepad,
+        }
+    }
+
+    pub fn poll(&mut self) {
+        if let Some(gamepad) = self.gamepad.as_mut() {
+            self.device.poll(&mut [
+                &mut self.keyboard,
+                &mut self.consumer,
+                &mut self.mouse,
+                &mut self.sensor_stream,
+                &mut self.keymap,
+                gamepad,
+            ]);
+        } else {
+            self.device.poll(&mut [
+                &mut self.keyboard,
+                &mut self.consumer,
+                &mut self.mouse,
+                &mut self.sensor_stream,
+                &mut self.keymap,
+            ]);
+        }
+    }
+
+    pub fn send_keys(&self, keys: &[Key]) -> Result<(), UsbError> {
+        match self.write_keys(keys) {
+            Err(UsbError::WouldBlock) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// 前のレポートがまだ読まれていなければ`UsbError::WouldBlock`(同じキーで送り直す)
+    pub fn write_keys(&self, keys: &[Key]) -> Result<(), UsbError> {
+        let (keyboard, consumer) = reports::reports(keys);
+        self.keyboard.write_report(&keyboard.to_bytes())?;
+        self.consumer.write_report(&consumer.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// 前のレポートがまだ読まれていなければ`UsbError::WouldBlock`(移動量は溜めておいて次で送る)
+    pub fn write_mouse(&self, report: &MouseReport) -> Result<(), UsbError> {
+        self.mouse.write_report(&report.to_bytes())?;
+        Ok(())
+    }
+
+    /// ホストが読んでいなければ捨てる
+    pub fn write_sensor_values(&mut self, raw: &[[u16; 12]; 4], filtered: &[[u16; 12]; 4]) {
+        self.sensor_stream.write_frame(raw, filtered);
+    }
+
+    /// ホストから届いたキーマップの読み書きのリクエストを一度だけ返す
+    pub fn take_keymap_request(&mut self) -> Option<[u8; KEYMAP_REPORT_SIZE]> {
+        self.keymap.take_request()
+    }
+
+    /// ホストが前の応答を読んでいなければ捨てる(ホストは読めなければ送り直す)
+    pub fn send_keymap_response(
+        &self,
+        response: &[u8; KEYMAP_REPORT_SIZE],
+    ) -> Result<(), UsbError> {
+        ignore_would_block(self.keymap.write_response(response))
+    }
+
+    pub fn send_gamepad(&self, report: &GamepadReport) -> Result<(), UsbError> {
+        match self.gamepad.as_ref() {
+            Some(gamepad) => ignore_would_block(gamepad.write_report(&report.to_bytes())),
+            None => Ok(()),
+        }
+    }
+}
+
+// 毎回全体の状態を送っているので、読まれていないレポートは次で上書きされればよい
+fn ignore_would_block(result: usb_device::Result<usize>) -> Result<(), UsbError> {
+    match result {
+        Ok(_) | Err(UsbError::WouldBlock) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,109 @@
+use usb_device::{
+    class_prelude::*,
+    control::{Recipient, Request, RequestType},
+};
+
+const HID_DESCRIPTOR_TYPE: u8 = 0x21;
+const HID_REPORT_DESCRIPTOR_TYPE: u8 = 0x22;
+pub(super) const HID_REQUEST_SET_REPORT: u8 = 0x09;
+const HID_REQUEST_SET_IDLE: u8 = 0x0a;
+const HID_REQUEST_SET_PROTOCOL: u8 = 0x0b;
+
+/// Inputレポートだけを送るHIDインターフェース
+///
+/// SET_REPORT(キーボードのLEDなど)は受け取って捨てる
+pub struct HidClass<'a, B: UsbBus> {
+    interface: InterfaceNumber,
+    endpoint: EndpointIn<'a, B>,
+    report_descriptor: &'static [u8],
+    boot_protocol: u8,
+}
+
+impl<'a, B: UsbBus> HidClass<'a, B> {
+    /// `boot_protocol`はブートインターフェースでなければ0(キーボードは1、マウスは2)
+    pub fn new(
+        alloc: &'a UsbBusAllocator<B>,
+        report_descriptor: &'static [u8],
+        max_packet_size: u16,
+        boot_protocol: u8,
+    ) -> HidClass<'a, B> {
+        HidClass {
+            interface: alloc.interface(),
+            endpoint: alloc.interrupt(max_packet_size, 1),
+            report_descriptor,
+            boot_protocol,
+        }
+    }
+
+    pub fn endpoint_address(&self) -> EndpointAddress {
+        self.endpoint.address()
+    }
+
+    /// 前のレポートがまだ読まれていなければ`UsbError::WouldBlock`
+    pub fn write_report(&self, report: &[u8]) -> usb_device::Result<usize> {
+        self.endpoint.write(report)
+    }
+
+    pub(super) fn is_own_request(&self, req: &Request) -> bool {
+        req.recipient == Recipient::Interface && req.index == u8::from(self.interface) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for HidClass<'_, B> {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        let subclass = if self.boot_protocol == 0 { 0x00 } else { 0x01 };
+        writer.interface(self.interface, 0x03, subclass, self.boot_protocol)?;
+        let len = self.report_descriptor.len() as u16;
+        writer.write(
+            HID_DESCRIPTOR_TYPE,
+            &[
+                0x11, // bcdHID 1.11
+                0x01,
+                0x00, // bCountryCode
+                0x01, // bNumDescriptors
+                HID_REPORT_DESCRIPTOR_TYPE,
+                len as u8,
+                (len >> 8) as u8,
+            ],
+        )?;
+        writer.endpoint(&self.endpoint)?;
+        Ok(())
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = xfer.request();
+        if !self.is_own_request(req) {
+            return;
+        }
+
+        if req.request_type == RequestType::Standard
+            && req.request == Request::GET_DESCRIPTOR
+            && (req.value >> 8) as u8 == HID_REPORT_DESCRIPTOR_TYPE
+        {
+            xfer.accept_with_static(self.report_descriptor).ok();
+        } else {
+            xfer.reject().ok();
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = xfer.request();
+        if !self.is_own_request(req) {
+            return;
+        }
+
+        if req.request_type == RequestType::Class
+            && matches!(
+                req.request,
+                HID_REQUEST_SET_REPORT | HID_REQUEST_SET_IDLE | HID_REQUEST_SET_PROTOCOL
+            )
+        {
+            xfer.accept().ok();
+        } else {
+            xfer.reject().ok();
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use rustkbd::keyboard::Key;
+
+/// HIDのUsage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Usage {
+    None,
+    Keyboard(u8),
+    /// Shiftと一緒に送る
+    Shifted(u8),
+    /// キーボードレポートの修飾キーのビット
+    Modifier(u8),
+    Consumer(u16),
+}
+
+impl From<Key> for Usage {
+    fn from(key: Key) -> Self {
+        use Usage::*;
+        match key {
+            Key::A => Keyboard(0x04),
+            Key::B => Keyboard(0x05),
+            Key::C => Keyboard(0x06),
+            Key::D => Keyboard(0x07),
+            Key::E => Keyboard(0x08),
+            Key::F => Keyboard(0x09),
+            Key::G => Keyboard(0x0a),
+            Key::H => Keyboard(0x0b),
+            Key::I => Keyboard(0x0c),
+            Key::J => Keyboard(0x0d),
+            Key::K => Keyboard(0x0e),
+            Key::L => Keyboard(0x0f),
+            Key::M => Keyboard(0x10),
+            Key::N => Keyboard(0x11),
+            Key::O => Keyboard(0x12),
+            Key::P => Keyboard(0x13),
+            Key::Q => Keyboard(0x14),
+            Key::R => Keyboard(0x15),
+            Key::S => Keyboard(0x16),
+            Key::T => Keyboard(0x17),
+            Key::U => Keyboard(0x18),
+            Key::V => Keyboard(0x19),
+            Key::W => Keyboard(0x1a),
+            Key::X => Keyboard(0x1b),
+            Key::Y => Keyboard(0x1c),
+            Key::Z => Keyboard(0x1d),
+            Key::Digit1 => Keyboard(0x1e),
+            Key::Digit2 => Keyboard(0x1f),
+            Key::Digit3 => Keyboard(0x20),
+            Key::Digit4 => Keyboard(0x21),
+            Key::Digit5 => Keyboard(0x22),
+            Key::Digit6 => Keyboard(0x23),
+            Key::Digit7 => Keyboard(0x24),
+            Key::Digit8 => Keyboard(0x25),
+            Key::Digit9 => Keyboard(0x26),
+            Key::Digit0 => Keyboard(0x27),
+            Key::Enter => Keyboard(0x28),
+            Key::Escape => Keyboard(0x29),
+            Key::Backspace => Keyboard(0x2a),
+            Key::Tab => Keyboard(0x2b),
+            Key::Space => Keyboard(0x2c),
+            Key::Minus => Keyboard(0x2d),
+            Key::Equal => Keyboard(0x2e),
+            Key::LeftBracket => Keyboard(0x2f),
+            Key::RightBracket => Keyboard(0x30),
+            Key::Backslash => Keyboard(0x31),
+            Key::Semicolon => Keyboard(0x33),
+            Key::Quote => Keyboard(0x34),
+            Key::Grave => Keyboard(0x35),
+            Key::Comma => Keyboard(0x36),
+            Key::Period => Keyboard(0x37),
+            Key::Slash => Keyboard(0x38),
+            Key::F1 => Keyboard(0x3a),
+            Key::F2 => Keyboard(0x3b),
+            Key::F3 => Keyboard(0x3c),
+            Key::F4 => Keyboard(0x3d),
+            Key::F5 => Keyboard(0x3e),
+            Key::F6 => Keyboard(0x3f),
+            Key::F7 => Keyboard(0x40),
+            Key::F8 => Keyboard(0x41),
+            Key::F9 => Keyboard(0x42),
+            Key::F10 => Keyboard(0x43),
+            Key::F11 => Keyboard(0x44),
+            Key::F12 => Keyboard(0x45),
+            Key::Delete => Keyboard(0x4c),
+            Key::Right => Keyboard(0x4f),
+            Key::Left => Keyboard(0x50),
+            Key::Down => Keyboard(0x51),
+            Key::Up => Keyboard(0x52),
+
+            Key::Exclamation => Shifted(0x1e),
+            Key::At => Shifted(0x1f),
+            Key::Hash => Shifted(0x20),
+            Key::Dollar => Shifted(0x21),
+            Key::Percent => Shifted(0x22),
+            Key::Caret => Shifted(0x23),
+            Key::Ampersand => Shifted(0x24),
+            Key::Asterisk => Shifted(0x25),
+            Key::LeftParenthesis => Shifted(0x26),
+            Key::RightParenthesis => Shifted(0x27),
+            Key::Underscore => Shifted(0x2d),
+            Key::Plus => Shifted(0x2e),
+            Key::LeftCurlyBrace => Shifted(0x2f),
+            Key::RightCurlyBrace => Shifted(0x30),
+            Key::Pipe => Shifted(0x31),
+            Key::Colon => Shifted(0x33),
+            Key::DoubleQuote => Shifted(0x34),
+            Key::Tilde => Shifted(0x35),
+            Key::LessThan => Shifted(0x36),
+            Key::GreaterThan => Shifted(0x37),
+            Key::Question => Shifted(0x38),
+
+            Key::LeftControl => Modifier(0x01),
+            Key::LeftShift => Modifier(0x02),
+            Key::LeftAlt => Modifier(0x04),
+            Key::LeftGui => Modifier(0x08),
+            Key::RightControl => Modifier(0x10),
+            Key::RightShift => Modifier(0x20),
+            Key::RightAlt => Modifier(0x40),
+            Key::RightGui => Modifier(0x80),
+
+            Key::MediaPlayPause => Consumer(0xcd),
+            Key::MediaNextTrack => Consumer(0xb5),
+            Key::MediaPrevTrack => Consumer(0xb6),
+            Key::MediaMute => Consumer(0xe2),
+            Key::MediaVolumeUp => Consumer(0xe9),
+            Key::MediaVolumeDown => Consumer(0xea),
+
+            _ => None,
+        }
+    }
+}
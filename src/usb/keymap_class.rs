@@ -0,0 +1,87 @@
+use usb_device::{class_prelude::*, control::RequestType};
+
+use super::hid_class::{HidClass, HID_REQUEST_SET_REPORT};
+
+pub const KEYMAP_REPORT_SIZE: usize = 32;
+
+// Vendor-defined (0xFF00)の32バイトのInput/Outputレポート
+#[rustfmt::skip]
+const REPORT_DESCRIPTOR: [u8; 25] = [
+    0x06, 0x00, 0xff, // Usage Page (Vendor Defined 0xFF00)
+    0x09, 0x02, // Usage (0x02)
+    0xa1, 0x01, // Collection (Application)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, KEYMAP_REPORT_SIZE as u8, //   Report Count (32)
+    0x09, 0x02, //   Usage (0x02)
+    0x81, 0x02, //   Input (Data, Var, Abs)
+    0x09, 0x02, //   Usage (0x02)
+    0x91, 0x02, //   Output (Data, Var, Abs)
+    0xc0, // End Collection
+];
+
+/// ホストからキーマップを読み書きするVendor-defined HIDインターフェース
+///
+/// リクエストはSET_REPORTのOutputレポートで受け取り、応答はInputレポートで返す
+pub struct KeymapClass<'a, B: UsbBus> {
+    hid: HidClass<'a, B>,
+    request: Option<[u8; KEYMAP_REPORT_SIZE]>,
+}
+
+impl<'a, B: UsbBus> KeymapClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> KeymapClass<'a, B> {
+        KeymapClass {
+            hid: HidClass::new(alloc, &REPORT_DESCRIPTOR, KEYMAP_REPORT_SIZE as u16, 0),
+            request: None,
+        }
+    }
+
+    /// 受け取ったリクエストを一度だけ返す
+    pub fn take_request(&mut self) -> Option<[u8; KEYMAP_REPORT_SIZE]> {
+        self.request.take()
+    }
+
+    pub fn write_response(&self, response: &[u8; KEYMAP_REPORT_SIZE]) -> usb_device::Result<usize> {
+        self.hid.write_report(response)
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for KeymapClass<'_, B> {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        self.hid.get_configuration_descriptors(writer)
+    }
+
+    fn reset(&mut self) {
+        self.request = None;
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        self.hid.control_in(xfer);
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = xfer.request();
+        if !(self.hid.is_own_request(req)
+            && req.request_type == RequestType::Class
+            && req.request == HID_REQUEST_SET_REPORT)
+        {
+            self.hid.control_out(xfer);
+            return;
+        }
+
+        // 前のリクエストを処理し終えるまでは受け付けない(ホストは応答を読んでから次を送る)
+        match xfer.data().try_into() {
+            Ok(request) if self.request.is_none() => {
+                self.request = Some(request);
+                xfer.accept().ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use rustkbd::keyboard::Key;
+
+use super::key_usage::Usage;
+
+// ブートキーボードと同じ形式(修飾キー、予約、キー6個)
+#[rustfmt::skip]
+pub const KEYBOARD_REPORT_DESCRIPTOR: [u8; 63] = [
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xa1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Keyboard)
+    0x19, 0xe0, //   Usage Minimum (Left Control)
+    0x29, 0xe7, //   Usage Maximum (Right GUI)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Var, Abs)
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x01, //   Input (Const)
+    0x95, 0x05, //   Report Count (5)
+    0x75, 0x01, //   Report Size (1)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (Num Lock)
+    0x29, 0x05, //   Usage Maximum (Kana)
+    0x91, 0x02, //   Output (Data, Var, Abs)
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x03, //   Report Size (3)
+    0x91, 0x01, //   Output (Const)
+    0x95, 0x06, //   Report Count (6)
+    0x75, 0x08, //   Report Size (8)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0xff, //   Logical Maximum (255)
+    0x05, 0x07, //   Usage Page (Keyboard)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0xff, //   Usage Maximum (255)
+    0x81, 0x00, //   Input (Data, Array, Abs)
+    0xc0, // End Collection
+];
+
+#[rustfmt::skip]
+pub const CONSUMER_REPORT_DESCRIPTOR: [u8; 23] = [
+    0x05, 0x0c, // Usage Page (Consumer)
+    0x09, 0x01, // Usage (Consumer Control)
+    0xa1, 0x01, // Collection (Application)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xff, 0x03, //   Logical Maximum (0x3ff)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x2a, 0xff, 0x03, //   Usage Maximum (0x3ff)
+    0x75, 0x10, //   Report Size (16)
+    0x95, 0x01, //   Report Count (1)
+    0x81, 0x00, //   Input (Data, Array, Abs)
+    0xc0, // End Collection
+];
+
+// X, Y, Rx, Ryの4軸
+#[rustfmt::skip]
+pub const GAMEPAD_REPORT_DESCRIPTOR: [u8; 25] = [
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x05, // Usage (Game Pad)
+    0xa1, 0x01, // Collection (Application)
+    0x09, 0x30, //   Usage (X)
+    0x09, 0x31, //   Usage (Y)
+    0x09, 0x33, //   Usage (Rx)
+    0x09, 0x34, //   Usage (Ry)
+    0x15, 0x81, //   Logical Minimum (-127)
+    0x25, 0x7f, //   Logical Maximum (127)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x04, //   Report Count (4)
+    0x81, 0x02, //   Input (Data, Var, Abs)
+    0xc0, // End Collection
+];
+
+// ボタン3つと、X, Y, ホイール, 横スクロール(AC Pan)の相対値
+#[rustfmt::skip]
+pub const MOUSE_REPORT_DESCRIPTOR: [u8; 61] = [
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x02, // Usage (Mouse)
+    0xa1, 0x01, // Collection (Application)
+    0x09, 0x01, //   Usage (Pointer)
+    0xa1, 0x00, //   Collection (Physical)
+    0x05, 0x09, //     Usage Page (Button)
+    0x19, 0x01, //     Usage Minimum (1)
+    0x29, 0x03, //     Usage Maximum (3)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x95, 0x03, //     Report Count (3)
+    0x75, 0x01, //     Report Size (1)
+    0x81, 0x02, //     Input (Data, Var, Abs)
+    0x95, 0x01, //     Report Count (1)
+    0x75, 0x05, //     Report Size (5)
+    0x81, 0x01, //     Input (Const)
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x09, 0x38, //     Usage (Wheel)
+    0x15, 0x81, //     Logical Minimum (-127)
+    0x25, 0x7f, //     Logical Maximum (127)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x03, //     Report Count (3)
+    0x81, 0x06, //     Input (Data, Var, Rel)
+    0x05, 0x0c, //     Usage Page (Consumer)
+    0x0a, 0x38, 0x02, //     Usage (AC Pan)
+    0x95, 0x01, //     Report Count (1)
+    0x81, 0x06, //     Input (Data, Var, Rel)
+    0xc0, //   End Collection
+    0xc0, // End Collection
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyboardReport {
+    pub modifiers: u8,
+    pub keys: [u8; 6],
+}
+
+impl KeyboardReport {
+    pub fn to_bytes(self) -> [u8; 8] {
+        let mut bytes = [0; 8];
+        bytes[0] = self.modifiers;
+        bytes[2..].copy_from_slice(&self.keys);
+        bytes
+    }
+}
+
+/// キーボードのレポートとメディアキーのレポートを作る(7個目以降のキーは捨てる)
+pub fn reports(keys: &[Key]) -> (KeyboardReport, u16) {
+    let mut report = KeyboardReport::default();
+    let mut consumer = 0;
+    let mut count = 0;
+    for key in keys {
+        let code = match Usage::from(*key) {
+            Usage::None => continue,
+            Usage::Modifier(bit) => {
+                report.modifiers |= bit;
+                continue;
+            }
+            Usage::Consumer(usage) => {
+                consumer = usage;
+                continue;
+            }
+            Usage::Keyboard(code) => code,
+            Usage::Shifted(code) => {
+                report.modifiers |= 0x02;
+                code
+            }
+        };
+        if count < report.keys.len() && !report.keys[..count].contains(&code) {
+            report.keys[count] = code;
+            count += 1;
+        }
+    }
+    (report, consumer)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GamepadReport {
+    pub x: i8,
+    pub y: i8,
+    pub rx: i8,
+    pub ry: i8,
+}
+
+impl GamepadReport {
+    pub fn to_bytes(self) -> [u8; 4] {
+        [self.x as u8, self.y as u8, self.rx as u8, self.ry as u8]
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MouseReport {
+    pub buttons: u8,
+    pub x: i8,
+    pub y: i8,
+    pub wheel: i8,
+    pub pan: i8,
+}
+
+impl MouseReport {
+    /// まだ送っていない移動量に足す
+    pub fn add(&mut self, x: i8, y: i8, wheel: i8, pan: i8) {
+        let add = |a: i8, b: i8| a.saturating_add(b).max(-127);
+        self.x = add(self.x, x);
+        self.y = add(self.y, y);
+        self.wheel = add(self.wheel, wheel);
+        self.pan = add(self.pan, pan);
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.x != 0 || self.y != 0 || self.wheel != 0 || self.pan != 0
+    }
+
+    pub fn to_bytes(self) -> [u8; 5] {
+        [
+            self.buttons,
+            self.x as u8,
+            self.y as u8,
+            self.wheel as u8,
+            self.pan as u8,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_movement_saturating_at_127() {
+        let mut report = MouseReport::default();
+        report.add(100, -100, 1, -1);
+        report.add(100, -100, 2, -2);
+        assert_eq!(
+            (report.x, report.y, report.wheel, report.pan),
+            (127, -127, 3, -3)
+        );
+        assert!(report.is_moving());
+    }
+}
@@ -0,0 +1,109 @@
+use usb_device::class_prelude::*;
+
+use super::hid_class::HidClass;
+
+const REPORT_SIZE: usize = 64;
+// レポートの先頭2バイトはフレームの通し番号と何番目のレポートか
+const CHUNK_SIZE: usize = REPORT_SIZE - 2;
+// 生の値とフィルタ後の値(どちらも行優先のu16 LE)
+const FRAME_SIZE: usize = 2 * 4 * 12 * 2;
+const CHUNKS: usize = FRAME_SIZE.div_ceil(CHUNK_SIZE);
+
+// Vendor-defined (0xFF00)の64バイトのInputレポート
+#[rustfmt::skip]
+const REPORT_DESCRIPTOR: [u8; 21] = [
+    0x06, 0x00, 0xff, // Usage Page (Vendor Defined 0xFF00)
+    0x09, 0x01, // Usage (0x01)
+    0xa1, 0x01, // Collection (Application)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, REPORT_SIZE as u8, //   Report Count (64)
+    0x09, 0x01, //   Usage (0x01)
+    0x81, 0x02, //   Input (Data, Var, Abs)
+    0xc0, // End Collection
+];
+
+/// センサーの値をホストに送るVendor-defined HIDインターフェース
+pub struct SensorStreamClass<'a, B: UsbBus> {
+    hid: HidClass<'a, B>,
+    frame: [u8; FRAME_SIZE],
+    sequence: u8,
+    // 送信中のフレームで次に送るレポート
+    chunk: Option<usize>,
+}
+
+impl<'a, B: UsbBus> SensorStreamClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> SensorStreamClass<'a, B> {
+        SensorStreamClass {
+            hid: HidClass::new(alloc, &REPORT_DESCRIPTOR, REPORT_SIZE as u16, 0),
+            frame: [0; FRAME_SIZE],
+            sequence: 0,
+            chunk: None,
+        }
+    }
+
+    /// 前のフレームを送り終えていなければ捨てる
+    pub fn write_frame(&mut self, raw: &[[u16; 12]; 4], filtered: &[[u16; 12]; 4]) {
+        if self.chunk.is_some() {
+            return;
+        }
+
+        let values = raw.iter().chain(filtered).flatten();
+        for (i, value) in values.enumerate() {
+            self.frame[i * 2..i * 2 + 2].copy_from_slice(&value.to_le_bytes());
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        self.chunk = Some(0);
+        self.write_chunk();
+    }
+
+    fn write_chunk(&mut self) {
+        let Some(chunk) = self.chunk else {
+            return;
+        };
+        if chunk >= CHUNKS {
+            self.chunk = None;
+            return;
+        }
+
+        let mut report = [0; REPORT_SIZE];
+        report[0] = self.sequence;
+        report[1] = chunk as u8;
+        let data = &self.frame[chunk * CHUNK_SIZE..FRAME_SIZE.min((chunk + 1) * CHUNK_SIZE)];
+        report[2..2 + data.len()].copy_from_slice(data);
+        match self.hid.write_report(&report) {
+            Ok(_) => self.chunk = Some(chunk + 1),
+            Err(UsbError::WouldBlock) => {}
+            // 設定前などは送らずに捨てる
+            Err(_) => self.chunk = None,
+        }
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for SensorStreamClass<'_, B> {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        self.hid.get_configuration_descriptors(writer)
+    }
+
+    fn reset(&mut self) {
+        self.chunk = None;
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr == self.hid.endpoint_address() {
+            self.write_chunk();
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        self.hid.control_in(xfer);
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        self.hid.control_out(xfer);
+    }
+}